@@ -0,0 +1,134 @@
+use super::announce::Announcer;
+use super::player::{rebuild_player, SpotifyPlayer};
+use super::stats::VoiceStatus;
+
+use librespot::playback::config::Bitrate;
+
+use serenity::http::Http;
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+/// How often the underrun counter is sampled.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Weight of the latest interval in the moving average of underruns per interval.
+const SMOOTHING: f64 = 0.3;
+
+/// Step the bitrate down once the average rises above this many underruns per interval.
+const STEP_DOWN_THRESHOLD: f64 = 1.0;
+
+/// The stream counts as stable while the average stays below this.
+const STABLE_THRESHOLD: f64 = 0.05;
+
+/// Step the bitrate back up after streaming this long without stutter.
+const STABLE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+fn lower(bitrate: Bitrate) -> Option<Bitrate> {
+    match bitrate {
+        Bitrate::Bitrate320 => Some(Bitrate::Bitrate160),
+        Bitrate::Bitrate160 => Some(Bitrate::Bitrate96),
+        Bitrate::Bitrate96 => None,
+    }
+}
+
+fn higher(bitrate: Bitrate) -> Option<Bitrate> {
+    match bitrate {
+        Bitrate::Bitrate96 => Some(Bitrate::Bitrate160),
+        Bitrate::Bitrate160 => Some(Bitrate::Bitrate320),
+        Bitrate::Bitrate320 => None,
+    }
+}
+
+/// The bitrate as a number, for messages.
+pub fn kbps(bitrate: Bitrate) -> u32 {
+    match bitrate {
+        Bitrate::Bitrate96 => 96,
+        Bitrate::Bitrate160 => 160,
+        Bitrate::Bitrate320 => 320,
+    }
+}
+
+/// Lowers the Spotify bitrate while the sink keeps running dry, and raises it again,
+/// up to `max`, once streaming was stable for `STABLE_PERIOD`. Each change restarts
+/// the Spotify player, resuming the current track.
+pub fn spawn(
+    player: Arc<Mutex<SpotifyPlayer>>,
+    announcer: Announcer,
+    http: Arc<Http>,
+    max: Bitrate,
+) {
+    tokio::spawn(async move {
+        let mut average = 0.0;
+        let mut last_underruns = player.lock().await.stats.underruns();
+        let mut stable_since = Instant::now();
+
+        loop {
+            sleep(CHECK_INTERVAL).await;
+
+            let (underruns, bitrate, streaming) = {
+                let player = player.lock().await;
+                (
+                    player.stats.underruns(),
+                    player.player_config.bitrate,
+                    player.stats.voice_status() == VoiceStatus::Streaming,
+                )
+            };
+
+            let new_underruns = underruns - last_underruns;
+            last_underruns = underruns;
+
+            // Time spent paused or out of voice says nothing about the connection
+            if !streaming {
+                stable_since = Instant::now();
+                continue;
+            }
+
+            average = SMOOTHING * new_underruns as f64 + (1.0 - SMOOTHING) * average;
+
+            if average >= STABLE_THRESHOLD {
+                stable_since = Instant::now();
+            }
+
+            let (next, reason) = if average > STEP_DOWN_THRESHOLD {
+                (lower(bitrate), "playback keeps stuttering")
+            } else if bitrate < max && stable_since.elapsed() >= STABLE_PERIOD {
+                (higher(bitrate), "playback has been stable")
+            } else {
+                (None, "")
+            };
+
+            let Some(next) = next else {
+                continue;
+            };
+
+            tracing::info!(
+                underruns_per_interval = average,
+                "Adaptive bitrate: {}, switching from {} to {} kbit/s",
+                reason,
+                kbps(bitrate),
+                kbps(next)
+            );
+            announcer.say(
+                http.clone(),
+                format!("Switching to {} kbit/s, {}.", kbps(next), reason),
+            );
+
+            if rebuild_player(&player, |player| player.player_config.bitrate = next)
+                .await
+                .is_none()
+            {
+                // Used when the player starts next
+                player.lock().await.player_config.bitrate = next;
+            }
+
+            // Rebuilding the player can run the buffer dry by itself
+            average = 0.0;
+            last_underruns = player.lock().await.stats.underruns();
+            stable_since = Instant::now();
+        }
+    });
+}
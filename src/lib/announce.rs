@@ -0,0 +1,174 @@
+use librespot::core::spotify_id::SpotifyId;
+use librespot::metadata::Track;
+
+use super::metadata::MetadataCache;
+
+use serenity::http::Http;
+use serenity::model::id::ChannelId;
+use serenity::model::mention::Mentionable;
+use serenity::prelude::TypeMapKey;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::time::{sleep, Duration};
+
+/// Wait this long after a track starts before announcing it, so skipping
+/// through several tracks only announces the one that sticks.
+const NOW_PLAYING_DEBOUNCE: Duration = Duration::from_secs(3);
+
+/// Wait this long after joining or leaving voice before announcing it, so
+/// reconnects and quick stop-starts don't post anything.
+const VOICE_DEBOUNCE: Duration = Duration::from_secs(10);
+
+/// Posts messages about playback to the configured announce channel.
+#[derive(Clone)]
+pub struct Announcer {
+    channel_id: Arc<Mutex<Option<ChannelId>>>,
+    generation: Arc<AtomicU64>,
+    last_track: Arc<Mutex<Option<SpotifyId>>>,
+    voice_generation: Arc<AtomicU64>,
+    // Voice channel the last voice announcement was about
+    announced_voice: Arc<Mutex<Option<ChannelId>>>,
+}
+
+pub struct AnnouncerKey;
+
+impl TypeMapKey for AnnouncerKey {
+    type Value = Announcer;
+}
+
+impl Announcer {
+    pub fn new(channel_id: Option<u64>) -> Announcer {
+        Announcer {
+            channel_id: Arc::new(Mutex::new(channel_id.map(ChannelId))),
+            generation: Arc::new(AtomicU64::new(0)),
+            last_track: Arc::new(Mutex::new(None)),
+            voice_generation: Arc::new(AtomicU64::new(0)),
+            announced_voice: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.channel_id().is_some()
+    }
+
+    /// Announce to `channel_id` from now on, or stop announcing if `None`.
+    pub fn set_channel_id(&self, channel_id: Option<u64>) {
+        *self.channel_id.lock().unwrap() = channel_id.map(ChannelId);
+    }
+
+    fn channel_id(&self) -> Option<ChannelId> {
+        *self.channel_id.lock().unwrap()
+    }
+
+    /// Post a plain message to the announce channel, if one is configured.
+    pub fn say(&self, http: Arc<Http>, content: String) {
+        let Some(channel_id) = self.channel_id() else {
+            return;
+        };
+
+        tokio::spawn(async move {
+            if let Err(error) = channel_id.say(&http, content).await {
+                tracing::warn!("Could not post announcement: {:?}", error);
+            }
+        });
+    }
+
+    /// Announce `track` once it has been playing for a moment.
+    /// Repeated events for the same track (unpausing, seeking) are ignored.
+    pub fn now_playing(
+        &self,
+        http: Arc<Http>,
+        metadata: Arc<MetadataCache>,
+        track: Track,
+        artist: String,
+    ) {
+        let Some(channel_id) = self.channel_id() else {
+            return;
+        };
+
+        {
+            let mut last_track = self.last_track.lock().unwrap();
+            if *last_track == Some(track.id) {
+                return;
+            }
+            *last_track = Some(track.id);
+        }
+
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let current_generation = self.generation.clone();
+
+        tokio::spawn(async move {
+            sleep(NOW_PLAYING_DEBOUNCE).await;
+
+            if current_generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+
+            let cover_url = metadata.cover_url(&track).await;
+
+            let result = channel_id
+                .send_message(&http, |m| {
+                    m.embed(|e| {
+                        e.title("Now playing")
+                            .description(format!("{} - {}", artist, track.name));
+
+                        if let Some(url) = cover_url {
+                            e.thumbnail(url);
+                        }
+
+                        e
+                    })
+                })
+                .await;
+
+            if let Err(error) = result {
+                tracing::warn!("Could not announce track: {:?}", error);
+            }
+        });
+    }
+
+    /// The bot now streams to `voice_channel_id`, or left voice if `None`. Announced
+    /// once it stayed that way for a moment and differs from the last announcement.
+    pub fn voice_channel(&self, http: Arc<Http>, voice_channel_id: Option<ChannelId>) {
+        let Some(channel_id) = self.channel_id() else {
+            return;
+        };
+
+        let generation = self.voice_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let announcer = self.clone();
+
+        tokio::spawn(async move {
+            sleep(VOICE_DEBOUNCE).await;
+
+            if announcer.voice_generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+
+            let content = {
+                let mut announced = announcer.announced_voice.lock().unwrap();
+                let content = match (*announced, voice_channel_id) {
+                    (None, Some(new)) => format!("Joined {}", new.mention()),
+                    (Some(old), Some(new)) if old != new => {
+                        format!("Moved to {}", new.mention())
+                    }
+                    (Some(old), None) => format!("Left {}", old.mention()),
+                    // Back where the last announcement said
+                    _ => return,
+                };
+                *announced = voice_channel_id;
+                content
+            };
+
+            if let Err(error) = channel_id.say(&http, content).await {
+                tracing::warn!("Could not announce voice channel: {:?}", error);
+            }
+        });
+    }
+
+    /// Forget the last announced track so it gets announced again when it resumes.
+    pub fn reset(&self) {
+        *self.last_track.lock().unwrap() = None;
+    }
+}
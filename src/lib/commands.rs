@@ -0,0 +1,1292 @@
+use crate::lib::adaptive::kbps;
+use crate::lib::loudness::Loudness;
+use crate::lib::lyrics::{Lyrics, KEYMASTER_CLIENT_ID};
+use crate::lib::metadata::MetadataCacheKey;
+use crate::lib::now_playing::NowPlaying;
+use crate::lib::play::PlayTarget;
+use crate::lib::player::{rebuild_player, SpotifyPlayerKey};
+use crate::lib::stats::{format_duration, VoiceStatus};
+use crate::lib::voice::{self, VoiceError};
+use crate::{reload_config, set_presence, ConfigKey, ShardManagerKey};
+
+use librespot::core::keymaster;
+
+use serenity::client::bridge::gateway::ShardId;
+use serenity::client::Context;
+use serenity::framework::standard::{
+    help_commands,
+    macros::{check, command, group, help, hook},
+    Args, CommandGroup, CommandOptions, CommandResult, DispatchError, HelpOptions, Reason,
+};
+use serenity::model::{channel::Message, id::UserId, user::OnlineStatus};
+
+use std::collections::{BTreeSet, HashSet};
+use std::time::Instant;
+
+use tokio::time::{timeout, Duration};
+
+use songbird::error::JoinError;
+
+#[group]
+#[description = "Control Spotify playback"]
+#[commands(
+    devices, transfer, play, seekpct, mute, unmute, volumeup, volumedown, crossfade, normalize
+)]
+#[checks(Authorized, CommandChannel)]
+struct Playback;
+
+#[group]
+#[description = "Inspect what the bot is doing"]
+#[commands(nowplaying, cover, lyrics, share, stats, audiostats, connection)]
+#[checks(Authorized, CommandChannel)]
+struct Status;
+
+#[group]
+#[description = "Recover the bot when something is stuck"]
+#[commands(reconnect, respotify, reload, status, clearpresence)]
+#[checks(Authorized, CommandChannel)]
+struct Admin;
+
+#[help]
+#[individual_command_tip = "Use `!help <command>` for details about a command."]
+#[strikethrough_commands_tip_in_guild = "~~Struck through~~ commands are only available to the authorized user."]
+#[lacking_conditions = "strike"]
+async fn help(
+    ctx: &Context,
+    msg: &Message,
+    args: Args,
+    help_options: &'static HelpOptions,
+    groups: &[&'static CommandGroup],
+    owners: HashSet<UserId>,
+) -> CommandResult {
+    let _ = help_commands::with_embeds(ctx, msg, args, help_options, groups, owners).await;
+    Ok(())
+}
+
+/// What commands start with.
+pub const COMMAND_PREFIX: &str = "!";
+
+/// Seconds a user has to wait between two commands that control Spotify,
+/// so rapid-fire commands don't pile up in the player.
+pub const CONTROL_COOLDOWN_SECS: u64 = 1;
+
+/// Seconds between two commands that restart the voice stream or the player.
+pub const RESTART_COOLDOWN_SECS: u64 = 5;
+
+/// Channels that were told where commands go, so it's only said once per channel.
+static REDIRECTED_CHANNELS: std::sync::Mutex<BTreeSet<u64>> =
+    std::sync::Mutex::new(BTreeSet::new());
+
+/// Tell the user how long to wait when a command is on cooldown, or where to use commands.
+#[hook]
+pub async fn dispatch_error(ctx: &Context, msg: &Message, error: DispatchError, _: &str) {
+    let info = match error {
+        DispatchError::Ratelimited(info) => info,
+        DispatchError::CheckFailed("CommandChannel", Reason::User(reply)) => {
+            if REDIRECTED_CHANNELS.lock().unwrap().insert(msg.channel_id.0) {
+                let _ = msg.reply(ctx, reply).await;
+            }
+            return;
+        }
+        _ => return,
+    };
+
+    // Only answer the first attempt, replying to every one would be spam too
+    if info.is_first_try {
+        let _ = msg
+            .reply(
+                ctx,
+                format!(
+                    "Slow down, try again in {:.1} seconds.",
+                    info.rate_limit.as_secs_f32()
+                ),
+            )
+            .await;
+    }
+}
+
+#[check]
+#[name = "Authorized"]
+async fn authorized_check(
+    ctx: &Context,
+    msg: &Message,
+    _: &mut Args,
+    _: &CommandOptions,
+) -> Result<(), Reason> {
+    let data = ctx.data.read().await;
+    let config = data.get::<ConfigKey>().unwrap();
+
+    if config.user_ids().contains(&msg.author.id) {
+        Ok(())
+    } else {
+        Err(Reason::Log(format!(
+            "Ignoring command from unauthorized user {}",
+            msg.author.id
+        )))
+    }
+}
+
+#[check]
+#[name = "CommandChannel"]
+async fn command_channel_check(
+    ctx: &Context,
+    msg: &Message,
+    _: &mut Args,
+    _: &CommandOptions,
+) -> Result<(), Reason> {
+    let data = ctx.data.read().await;
+    let config = data.get::<ConfigKey>().unwrap();
+
+    // Direct messages don't clutter a server, so they are always fine
+    if config.command_channels.is_empty()
+        || msg.guild_id.is_none()
+        || config.command_channels.contains(&msg.channel_id.0)
+    {
+        return Ok(());
+    }
+
+    let channels: Vec<String> = config
+        .command_channels
+        .iter()
+        .map(|channel_id| format!("<#{}>", channel_id))
+        .collect();
+
+    Err(Reason::User(format!(
+        "Commands are only accepted in {}.",
+        channels.join(", ")
+    )))
+}
+
+#[command]
+#[description = "List the Spotify Connect devices on the account"]
+async fn devices(ctx: &Context, msg: &Message) -> CommandResult {
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+    let tracker = player.lock().await.devices.clone();
+
+    let devices = tracker.devices().await;
+
+    if devices.iter().all(|device| device.is_bot) {
+        msg.reply(ctx, "No other Spotify Connect devices are available.")
+            .await?;
+        return Ok(());
+    }
+
+    let lines: Vec<String> = devices
+        .iter()
+        .map(|device| {
+            let mut line = if device.is_active {
+                format!("▶ {} (playing)", device.name)
+            } else {
+                format!("• {}", device.name)
+            };
+
+            if device.is_bot {
+                line.push_str(" (this bot)");
+            }
+
+            line
+        })
+        .collect();
+
+    msg.reply(ctx, lines.join("\n")).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Move playback to the given Spotify Connect device"]
+#[usage = "<device name>"]
+#[min_args(1)]
+#[bucket = "control"]
+async fn transfer(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let name = args.rest().trim().to_lowercase();
+
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+    let (tracker, connected) = {
+        let player = player.lock().await;
+        (player.devices.clone(), player.spirc.is_some())
+    };
+
+    if !connected {
+        msg.reply(
+            ctx,
+            "Join a voice channel first so I can show up in Spotify.",
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let devices = tracker.devices().await;
+
+    let Some(target) = devices
+        .iter()
+        .find(|device| device.name.to_lowercase() == name)
+    else {
+        msg.reply(ctx, format!("Could not find a device named \"{}\".", name))
+            .await?;
+        return Ok(());
+    };
+
+    let reply = match tracker.transfer(target) {
+        Ok(()) => format!("Transferring playback to {}.", target.name),
+        Err(error) => format!("Could not transfer playback: {}.", error),
+    };
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Play a track, album, playlist or artist from a Spotify URI or open.spotify.com link"]
+#[usage = "<spotify uri or link>"]
+#[example = "https://open.spotify.com/album/4aawyAB9vmqN3uQ7FjRGTy"]
+#[min_args(1)]
+#[bucket = "control"]
+async fn play(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let target = match PlayTarget::parse(args.rest()) {
+        Ok(target) => target,
+        Err(error) => {
+            msg.reply(ctx, format!("Can't play that: {}.", error))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+    let (tracker, session, connected) = {
+        let player = player.lock().await;
+        (
+            player.devices.clone(),
+            player.session.clone(),
+            player.spirc.is_some(),
+        )
+    };
+
+    if !connected {
+        msg.reply(
+            ctx,
+            "Join a voice channel first so I can show up in Spotify.",
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let (name, tracks) = match target.resolve(&session).await {
+        Ok(resolved) => resolved,
+        Err(_) => {
+            msg.reply(ctx, format!("Could not find {} on Spotify.", target.uri()))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    if tracks.is_empty() {
+        msg.reply(ctx, format!("{} has no tracks to play.", name))
+            .await?;
+        return Ok(());
+    }
+
+    tracker.play(target.uri(), &tracks);
+
+    msg.reply(ctx, format!("Playing {}.", name)).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Jump to a percentage of the current track, e.g. 50 for the middle"]
+#[usage = "<0-100>"]
+#[example = "50"]
+#[min_args(1)]
+#[bucket = "control"]
+async fn seekpct(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let percent = match args.single::<u32>() {
+        Ok(percent) if percent <= 100 => percent,
+        _ => {
+            msg.reply(ctx, "Give a percentage from 0 to 100, like `!seekpct 50`.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let (player, metadata) = {
+        let data = ctx.data.read().await;
+        (
+            data.get::<SpotifyPlayerKey>().unwrap().clone(),
+            data.get::<MetadataCacheKey>().unwrap().clone(),
+        )
+    };
+
+    let (tracker, track_id) = {
+        let player = player.lock().await;
+        let track_id = player.spirc.as_ref().and(player.stats.current_track());
+        (player.devices.clone(), track_id)
+    };
+
+    let Some(track_id) = track_id else {
+        msg.reply(ctx, "Nothing is playing.").await?;
+        return Ok(());
+    };
+
+    let Ok(track) = metadata.get::<librespot::metadata::Track>(track_id).await else {
+        msg.reply(ctx, "Could not look up the current track.")
+            .await?;
+        return Ok(());
+    };
+
+    let duration_ms = track.duration.max(0) as u32;
+    // Seeking to the very end would skip the track
+    let position_ms = (duration_ms as u64 * percent as u64 / 100)
+        .min(duration_ms.saturating_sub(1000) as u64) as u32;
+
+    tracker.seek(position_ms);
+
+    msg.reply(
+        ctx,
+        format!(
+            "Jumped to {} of {}.",
+            format_track_time(position_ms),
+            format_track_time(duration_ms)
+        ),
+    )
+    .await?;
+
+    Ok(())
+}
+
+const VOLUME_CONTROL_UNAVAILABLE: &str =
+    "Volume control is unavailable, VOLUME_CTRL is set to fixed.";
+
+#[command]
+#[description = "Silence playback without forgetting the volume"]
+#[bucket = "control"]
+async fn mute(ctx: &Context, msg: &Message) -> CommandResult {
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+
+    let mut player = player.lock().await;
+
+    let reply = if !player.volume_control() {
+        VOLUME_CONTROL_UNAVAILABLE
+    } else if player.mute() {
+        "Muted."
+    } else {
+        "Already muted."
+    };
+    drop(player);
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Restore the volume from before muting"]
+#[bucket = "control"]
+async fn unmute(ctx: &Context, msg: &Message) -> CommandResult {
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+
+    let mut player = player.lock().await;
+
+    let reply = if !player.volume_control() {
+        VOLUME_CONTROL_UNAVAILABLE
+    } else if player.unmute() {
+        "Unmuted."
+    } else {
+        "Not muted."
+    };
+    drop(player);
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+/// Change the volume by `VOLUME_STEP` percent in `direction` (1 or -1) and reply with the new level.
+async fn step_volume(ctx: &Context, msg: &Message, direction: i32) -> CommandResult {
+    let (player, step) = {
+        let data = ctx.data.read().await;
+        (
+            data.get::<SpotifyPlayerKey>().unwrap().clone(),
+            data.get::<ConfigKey>().unwrap().volume_step,
+        )
+    };
+
+    let mut player = player.lock().await;
+
+    let reply = if !player.volume_control() {
+        VOLUME_CONTROL_UNAVAILABLE.to_string()
+    } else {
+        format!(
+            "Volume: {}%.",
+            player.change_volume(direction * step as i32)
+        )
+    };
+    drop(player);
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Turn the volume up by VOLUME_STEP percent"]
+#[bucket = "control"]
+async fn volumeup(ctx: &Context, msg: &Message) -> CommandResult {
+    step_volume(ctx, msg, 1).await
+}
+
+#[command]
+#[description = "Turn the volume down by VOLUME_STEP percent"]
+#[bucket = "control"]
+async fn volumedown(ctx: &Context, msg: &Message) -> CommandResult {
+    step_volume(ctx, msg, -1).await
+}
+
+/// Longest accepted crossfade.
+const MAX_CROSSFADE_SECS: u64 = 12;
+
+/// Added to crossfade replies, librespot 0.4 can't fade between tracks yet.
+const CROSSFADE_UNAVAILABLE: &str =
+    "Note that crossfade isn't available with Spotify Connect playback yet, tracks still play \
+    back to back.";
+
+#[command]
+#[description = "Show or set the fade duration between tracks, 0 disables it"]
+#[usage = "[seconds]"]
+#[max_args(1)]
+#[bucket = "control"]
+async fn crossfade(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+
+    let reply = if args.is_empty() {
+        let crossfade = player.lock().await.crossfade;
+
+        if crossfade.is_zero() {
+            "Crossfade is off.".to_string()
+        } else {
+            format!(
+                "Crossfade is set to {} seconds. {}",
+                crossfade.as_secs(),
+                CROSSFADE_UNAVAILABLE
+            )
+        }
+    } else {
+        match args.single::<u64>() {
+            Ok(seconds) if seconds <= MAX_CROSSFADE_SECS => {
+                player.lock().await.crossfade = Duration::from_secs(seconds);
+
+                if seconds == 0 {
+                    "Crossfade turned off.".to_string()
+                } else {
+                    format!(
+                        "Crossfade set to {} seconds. {}",
+                        seconds, CROSSFADE_UNAVAILABLE
+                    )
+                }
+            }
+            _ => format!(
+                "The crossfade must be a number of seconds from 0 to {}.",
+                MAX_CROSSFADE_SECS
+            ),
+        }
+    };
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Show the current track and its loudness"]
+async fn nowplaying(ctx: &Context, msg: &Message) -> CommandResult {
+    let (player, metadata) = {
+        let data = ctx.data.read().await;
+        (
+            data.get::<SpotifyPlayerKey>().unwrap().clone(),
+            data.get::<MetadataCacheKey>().unwrap().clone(),
+        )
+    };
+
+    let (track_id, status, session) = {
+        let player = player.lock().await;
+        (
+            player.stats.current_track(),
+            player.stats.voice_status(),
+            player.session.clone(),
+        )
+    };
+
+    let Some(track_id) = track_id.filter(|_| status != VoiceStatus::NotInVoice) else {
+        msg.reply(ctx, "Nothing is playing.").await?;
+        return Ok(());
+    };
+
+    let (Some(now_playing), Ok(track)) = (
+        NowPlaying::current(&player, &metadata).await,
+        metadata.get::<librespot::metadata::Track>(track_id).await,
+    ) else {
+        msg.reply(ctx, "Could not look up the current track.")
+            .await?;
+        return Ok(());
+    };
+
+    let loudness = match Loudness::fetch(&session, &track).await {
+        Some(loudness) => format!(
+            "≈ {:.1} LUFS\nTrack gain {:+.1} dB, peak {:.2}\nAlbum gain {:+.1} dB, peak {:.2}",
+            loudness.track_lufs(),
+            loudness.track_gain_db,
+            loudness.track_peak,
+            loudness.album_gain_db,
+            loudness.album_peak,
+        ),
+        None => "Unknown".to_string(),
+    };
+
+    let title = if now_playing.is_playing {
+        "Now playing"
+    } else {
+        "Now playing (paused)"
+    };
+
+    let position = format!(
+        "{} / {}",
+        format_track_time(now_playing.position_ms),
+        format_track_time(now_playing.duration_ms)
+    );
+
+    msg.channel_id
+        .send_message(ctx, |m| {
+            m.reference_message(msg).embed(|e| {
+                e.title(title)
+                    .description(format!(
+                        "{} - {}\n{}",
+                        now_playing.artists.join(", "),
+                        now_playing.name,
+                        now_playing.album
+                    ))
+                    .field("Position", position, true)
+                    .field(
+                        "Volume",
+                        format!("{}%", now_playing.volume as u32 * 100 / u16::MAX as u32),
+                        true,
+                    )
+                    .field("Quality", format!("{} kbit/s", now_playing.bitrate), true)
+                    .field("Loudness", loudness, false);
+
+                if let Some(url) = now_playing.cover_url.as_ref() {
+                    e.thumbnail(url);
+                }
+
+                e
+            })
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Format a position in a track as `m:ss`.
+fn format_track_time(ms: u32) -> String {
+    let secs = ms / 1000;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+#[command]
+#[description = "Show the album art of the current track and a link to it"]
+async fn cover(ctx: &Context, msg: &Message) -> CommandResult {
+    let (player, metadata) = {
+        let data = ctx.data.read().await;
+        (
+            data.get::<SpotifyPlayerKey>().unwrap().clone(),
+            data.get::<MetadataCacheKey>().unwrap().clone(),
+        )
+    };
+
+    let (track_id, status) = {
+        let player = player.lock().await;
+        (player.stats.current_track(), player.stats.voice_status())
+    };
+
+    let Some(track_id) = track_id.filter(|_| status != VoiceStatus::NotInVoice) else {
+        msg.reply(ctx, "Nothing is playing.").await?;
+        return Ok(());
+    };
+
+    // Local files aren't on Spotify, so there's neither artwork nor a link
+    let track: librespot::metadata::Track = match metadata.get(track_id).await {
+        Ok(track) => track,
+        Err(_) => {
+            msg.reply(ctx, "The current track has no album art.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let link = track
+        .id
+        .to_base62()
+        .map(|id| format!("https://open.spotify.com/track/{}", id))
+        .unwrap_or_default();
+
+    let Some(cover_url) = metadata.largest_cover_url(&track).await else {
+        msg.reply(ctx, format!("{} has no album art.\n{}", track.name, link))
+            .await?;
+        return Ok(());
+    };
+
+    msg.channel_id
+        .send_message(ctx, |m| {
+            m.reference_message(msg)
+                .embed(|e| e.title(&track.name).url(&link).image(cover_url))
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Spotify entities that have a page on open.spotify.com.
+const SHAREABLE_KINDS: [&str; 5] = ["playlist", "album", "artist", "show", "episode"];
+
+/// Link to the context `uri` (e.g. `spotify:playlist:...`) on open.spotify.com,
+/// `None` for contexts without a page, like radio stations or liked songs.
+fn context_link(uri: &str) -> Option<String> {
+    let parts: Vec<&str> = uri.split(':').collect();
+
+    let (kind, id) = match parts.as_slice() {
+        ["spotify", kind, id] => (*kind, *id),
+        // Older playlist URIs include the owner
+        ["spotify", "user", _, "playlist", id] => ("playlist", *id),
+        _ => return None,
+    };
+
+    SHAREABLE_KINDS
+        .contains(&kind)
+        .then(|| format!("https://open.spotify.com/{}/{}", kind, id))
+}
+
+#[command]
+#[description = "Link the playlist or album that is playing, so others can open it in Spotify"]
+async fn share(ctx: &Context, msg: &Message) -> CommandResult {
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+
+    let (state, track_id) = {
+        let player = player.lock().await;
+        (player.devices.bot_state(), player.stats.current_track())
+    };
+
+    let Some(state) = state else {
+        msg.reply(ctx, "Nothing is playing.").await?;
+        return Ok(());
+    };
+
+    let track_link = track_id
+        .and_then(|track_id| track_id.to_base62().ok())
+        .map(|id| format!("https://open.spotify.com/track/{}", id));
+
+    let reply = match (context_link(state.get_context_uri()), track_link) {
+        (Some(context), _) => context,
+        (None, Some(track)) => format!(
+            "What is playing can't be shared, like a radio station or liked songs. \
+            Here is the current track instead:\n{}",
+            track
+        ),
+        (None, None) => "What is playing can't be shared.".to_string(),
+    };
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+/// Discord cuts embed descriptions off at 4096 characters.
+const MAX_LYRICS_LENGTH: usize = 4000;
+
+/// Lines shown before the current one when the lyrics don't fit.
+const LYRICS_CONTEXT_LINES: usize = 3;
+
+#[command]
+#[description = "Show the lyrics of the current track, with the line being sung in bold"]
+async fn lyrics(ctx: &Context, msg: &Message) -> CommandResult {
+    let (player, metadata) = {
+        let data = ctx.data.read().await;
+        (
+            data.get::<SpotifyPlayerKey>().unwrap().clone(),
+            data.get::<MetadataCacheKey>().unwrap().clone(),
+        )
+    };
+
+    let (track_id, status, position, session) = {
+        let player = player.lock().await;
+        (
+            player.stats.current_track(),
+            player.stats.voice_status(),
+            player.position(),
+            player.session.clone(),
+        )
+    };
+
+    let Some(track_id) = track_id.filter(|_| status != VoiceStatus::NotInVoice) else {
+        msg.reply(ctx, "Nothing is playing.").await?;
+        return Ok(());
+    };
+
+    let lyrics = match Lyrics::fetch(&session, track_id).await {
+        Ok(Some(lyrics)) => lyrics,
+        Ok(None) => {
+            msg.reply(ctx, "Spotify has no lyrics for this track.")
+                .await?;
+            return Ok(());
+        }
+        Err(error) => {
+            tracing::warn!("Could not fetch lyrics: {}", error);
+            msg.reply(ctx, "Could not fetch the lyrics, try again later.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let current = position.and_then(|(position_ms, _)| lyrics.current_line(position_ms));
+
+    // Start a little before the current line, in case the lyrics are too long to show in full
+    let first = current.map_or(0, |current| current.saturating_sub(LYRICS_CONTEXT_LINES));
+    let mut text = String::new();
+    for (index, line) in lyrics.lines.iter().enumerate().skip(first) {
+        let line = if Some(index) == current && !line.words.trim().is_empty() {
+            format!("**{}**\n", line.words.trim())
+        } else {
+            format!("{}\n", line.words)
+        };
+
+        if text.len() + line.len() > MAX_LYRICS_LENGTH {
+            text.push('…');
+            break;
+        }
+        text.push_str(&line);
+    }
+    if first > 0 {
+        text.insert_str(0, "…\n");
+    }
+
+    let name = metadata
+        .get::<librespot::metadata::Track>(track_id)
+        .await
+        .map(|track| track.name)
+        .unwrap_or_else(|_| "Lyrics".to_string());
+
+    msg.channel_id
+        .send_message(ctx, |m| {
+            m.reference_message(msg).embed(|e| {
+                e.title(name).description(text);
+
+                if !lyrics.provider.is_empty() {
+                    e.footer(|f| f.text(format!("Lyrics provided by {}", lyrics.provider)));
+                }
+
+                e
+            })
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Show uptime and session information"]
+async fn stats(ctx: &Context, msg: &Message) -> CommandResult {
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+    let player = player.lock().await;
+
+    let session = if player.session.is_invalid() {
+        "disconnected"
+    } else {
+        "connected"
+    };
+
+    let voice = match player.stats.voice_channel() {
+        Some((guild_id, channel_id)) => format!(
+            "{} in <#{}> (guild {})",
+            player.stats.voice_status(),
+            channel_id,
+            guild_id
+        ),
+        None => player.stats.voice_status().to_string(),
+    };
+
+    let mut reply = format!(
+        "Uptime: {}\n\
+        Spotify session: {}\n\
+        Tracks played: {}\n\
+        Listening time: {}\n\
+        Reconnects: {}\n\
+        Bitrate: {:?}\n\
+        Voice: {}\n\
+        Underruns: {}\n\
+        Stalls: {}\n\
+        Dropped frames: {}",
+        format_duration(player.stats.uptime()),
+        session,
+        player.stats.tracks_played(),
+        format_duration(player.stats.listening_time()),
+        player.stats.reconnects(),
+        player.player_config.bitrate,
+        voice,
+        player.stats.underruns(),
+        player.stats.stalls(),
+        player.stats.dropped_frames(),
+    );
+
+    let persisted = ctx
+        .data
+        .read()
+        .await
+        .get::<ConfigKey>()
+        .unwrap()
+        .stats_path
+        .is_some();
+
+    if persisted {
+        let lifetime = player.stats.lifetime();
+
+        reply.push_str(&format!(
+            "\n\nAll time:\n\
+            Tracks played: {}\n\
+            Listening time: {}\n\
+            Reconnects: {}",
+            lifetime.tracks_played,
+            format_duration(Duration::from_secs(lifetime.listening_secs)),
+            lifetime.reconnects,
+        ));
+    }
+    drop(player);
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Show the state of the audio pipeline, for bug reports about stuttering"]
+async fn audiostats(ctx: &Context, msg: &Message) -> CommandResult {
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+    let player = player.lock().await;
+
+    let sink = player.emitted_sink.diagnostics();
+
+    let resampler = match sink.resampler_chunk_size {
+        Some(chunk_size) => format!(
+            "FFT {} Hz -> {} Hz, {} frame chunks",
+            sink.input_rate, sink.output_rate, chunk_size
+        ),
+        None => format!("none ({} Hz)", sink.output_rate),
+    };
+
+    let track = match player.track_handle.as_ref() {
+        Some(track_handle) => match track_handle.get_info().await {
+            Ok(state) => format!("{:?}, {:?} in", state.playing, state.position),
+            Err(error) => format!("{:?}", error),
+        },
+        None => "none".to_string(),
+    };
+
+    let reply = format!(
+        "```\n\
+        source bitrate: {:?}\n\
+        sample format:  {:?}\n\
+        resampler:      {}\n\
+        buffer:         {}/{} frames ({} ms), prebuffer {} frames\n\
+        overrun policy: {:?}\n\
+        underrun policy: {:?}\n\
+        underruns:      {}\n\
+        stalls:         {}\n\
+        dropped frames: {}\n\
+        voice:          {}\n\
+        songbird track: {}\n\
+        ```",
+        player.player_config.bitrate,
+        sink.sample_format,
+        resampler,
+        sink.buffered,
+        sink.capacity,
+        sink.buffered as u64 * 1000 / sink.output_rate as u64,
+        sink.prebuffer,
+        sink.overrun,
+        sink.underrun,
+        player.stats.underruns(),
+        player.stats.stalls(),
+        player.stats.dropped_frames(),
+        player.stats.voice_status(),
+        track,
+    );
+    drop(player);
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+/// Give up measuring the Spotify round trip after this long.
+const SPOTIFY_PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[command]
+#[description = "Show the Spotify access point and the latency to Spotify and Discord, to tell where stutter comes from"]
+async fn connection(ctx: &Context, msg: &Message) -> CommandResult {
+    let (player, shard_manager) = {
+        let data = ctx.data.read().await;
+        (
+            data.get::<SpotifyPlayerKey>().unwrap().clone(),
+            data.get::<ShardManagerKey>().cloned(),
+        )
+    };
+
+    let (session, voice_channel) = {
+        let player = player.lock().await;
+        (player.session.clone(), player.stats.voice_channel())
+    };
+
+    // A token request is a small Mercury round trip through the access point
+    let spotify = if session.is_invalid() {
+        "closed, logging in again".to_string()
+    } else {
+        let started = Instant::now();
+        let token = keymaster::get_token(&session, KEYMASTER_CLIENT_ID, "streaming");
+
+        match timeout(SPOTIFY_PING_TIMEOUT, token).await {
+            Ok(Ok(_)) => format!("{} ms", started.elapsed().as_millis()),
+            Ok(Err(_)) => "request failed".to_string(),
+            Err(_) => format!("no answer within {:?}", SPOTIFY_PING_TIMEOUT),
+        }
+    };
+
+    let gateway = match shard_manager {
+        Some(shard_manager) => {
+            let shard_manager = shard_manager.lock().await;
+            let runners = shard_manager.runners.lock().await;
+
+            match runners
+                .get(&ShardId(ctx.shard_id))
+                .and_then(|runner| runner.latency)
+            {
+                Some(latency) => format!("{} ms", latency.as_millis()),
+                None => "not measured yet".to_string(),
+            }
+        }
+        None => "unknown".to_string(),
+    };
+
+    let voice = match voice_channel {
+        Some((guild_id, _)) => {
+            let manager = songbird::get(ctx)
+                .await
+                .expect("Songbird Voice client placed in at initialization.");
+
+            let endpoint = match manager.get(guild_id) {
+                Some(handler) => handler
+                    .lock()
+                    .await
+                    .current_connection()
+                    .map(|connection| connection.endpoint.clone()),
+                None => None,
+            };
+
+            endpoint.unwrap_or_else(|| "connecting".to_string())
+        }
+        None => "not in voice".to_string(),
+    };
+
+    let reply = format!(
+        "```\n\
+        spotify access point: unknown\n\
+        spotify round trip:   {}\n\
+        discord gateway:      {}\n\
+        voice server:         {}\n\
+        ```",
+        spotify, gateway, voice,
+    );
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Rejoin the voice channel and restart the audio stream"]
+#[bucket = "restart"]
+async fn reconnect(ctx: &Context, msg: &Message) -> CommandResult {
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+
+    let reply = match voice::reconnect(ctx, &player).await {
+        Ok(()) => "Reconnected to voice.".to_string(),
+        Err(VoiceError::Join(JoinError::NoCall)) => {
+            "I'm not playing in a voice channel.".to_string()
+        }
+        Err(error) => format!("Could not reconnect: {}.", error),
+    };
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Restart the Spotify player without leaving voice, resuming the current track"]
+#[bucket = "restart"]
+async fn respotify(ctx: &Context, msg: &Message) -> CommandResult {
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+
+    let reply = match rebuild_player(&player, |_| {}).await {
+        Some(true) => "Restarted the Spotify player, resuming playback.",
+        Some(false) => "Restarted the Spotify player.",
+        None => "The Spotify player isn't running.",
+    };
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Turn loudness normalisation on or off, restarting the Spotify player"]
+#[usage = "on|off"]
+#[bucket = "control"]
+async fn normalize(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+
+    let normalisation = match args.single::<String>().ok().as_deref() {
+        Some("on") => true,
+        Some("off") => false,
+        _ => {
+            let state = if player.lock().await.player_config.normalisation {
+                "on"
+            } else {
+                "off"
+            };
+
+            msg.reply(
+                ctx,
+                format!("Normalisation is {}, use `!normalize on|off`.", state),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let state = if normalisation { "on" } else { "off" };
+
+    let reply = match rebuild_player(&player, |player| {
+        player.player_config.normalisation = normalisation
+    })
+    .await
+    {
+        Some(_) => format!("Normalisation is {}.", state),
+        None => {
+            // Applied the next time the player starts
+            player.lock().await.player_config.normalisation = normalisation;
+            format!("Normalisation is {}, from the next track you play.", state)
+        }
+    };
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Reload the settings that don't need a restart from the config"]
+async fn reload(ctx: &Context, msg: &Message) -> CommandResult {
+    let reply = match reload_config(ctx).await {
+        Ok(()) => "Reloaded the config.".to_string(),
+        Err(error) => format!("Could not reload the config: {}", error),
+    };
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+#[command]
+#[aliases(whoami)]
+#[description = "Show the settings the bot is running with, leaving out secrets"]
+async fn status(ctx: &Context, msg: &Message) -> CommandResult {
+    let (config, player) = {
+        let data = ctx.data.read().await;
+        (
+            data.get::<ConfigKey>().unwrap().clone(),
+            data.get::<SpotifyPlayerKey>().unwrap().clone(),
+        )
+    };
+
+    // Commands like !normalize change these without touching the config
+    let (bitrate, normalisation, device_name) = {
+        let player = player.lock().await;
+        (
+            player.player_config.bitrate,
+            player.player_config.normalisation,
+            player.device_name.clone(),
+        )
+    };
+
+    let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+
+    let mut features = Vec::new();
+    if config.spotify_bot_autoplay || config.radio_mode {
+        features.push("autoplay");
+    }
+    if config.radio_mode {
+        features.push("radio mode");
+    }
+    if config.initial_context.is_some() {
+        features.push("initial context");
+    }
+    if config.track_gain_only {
+        features.push("track gain only");
+    }
+    if config.adaptive_bitrate {
+        features.push("adaptive bitrate");
+    }
+    if config.announce_channel_id.is_some() {
+        features.push("announcements");
+    }
+    if config.announce_voice {
+        features.push("voice announcements");
+    }
+    if config.duck_level.is_some() {
+        features.push("ducking");
+    }
+    if config.filter_explicit {
+        features.push("explicit filter");
+    }
+    if config.record_path.is_some() {
+        features.push("recording");
+    }
+    if config.stats_path.is_some() {
+        features.push("stats file");
+    }
+    if config.warmup {
+        features.push("warmup");
+    }
+    if config.voice_rejoin {
+        features.push("voice rejoin");
+    }
+    let features = if features.is_empty() {
+        "none".to_string()
+    } else {
+        features.join(", ")
+    };
+
+    let reply = format!(
+        "```\n\
+        device:           {} (audio dongle)\n\
+        bitrate:          {} kbit/s\n\
+        normalisation:    {}\n\
+        latency profile:  {:?}\n\
+        sample format:    {:?} at {} Hz\n\
+        volume control:   {:?}\n\
+        authorized users: {} ({:?})\n\
+        command prefix:   {}\n\
+        features:         {}\n\
+        ```",
+        device_name,
+        kbps(bitrate),
+        on_off(normalisation),
+        config.audio_latency_profile,
+        config.sample_format,
+        config.output_sample_rate,
+        config.volume_ctrl,
+        config.user_ids().len(),
+        config.multi_user_policy,
+        COMMAND_PREFIX,
+        features,
+    );
+
+    msg.reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Clear the bot's presence, e.g. when it still shows a track that stopped"]
+async fn clearpresence(ctx: &Context, msg: &Message) -> CommandResult {
+    // Also replaces what a reconnect would restore, so the old track doesn't come back
+    set_presence(ctx, None, OnlineStatus::Online).await;
+
+    msg.reply(
+        ctx,
+        "Cleared the presence, it shows the next track that starts.",
+    )
+    .await?;
+
+    Ok(())
+}
@@ -1,8 +1,116 @@
 use figment::{
-    providers::{Env, Format, Toml},
+    providers::{Env, Format, Serialized, Toml},
     Error, Figment,
 };
-use serde::Deserialize;
+use librespot::core::authentication::Credentials;
+use librespot::protocol::authentication::AuthenticationType;
+use serde::{de, Deserialize, Deserializer};
+use serenity::model::gateway::Activity;
+use serenity::model::id::UserId;
+
+use super::player::{
+    LatencyProfile, OverrunPolicy, SampleFormat, UnderrunPolicy, VolumeControl,
+    DEFAULT_LOGIN_TIMEOUT,
+};
+
+use std::{env, fs};
+
+/// Settings that can also be read from a file named by `<NAME>_FILE`.
+const SECRETS: [&str; 3] = ["DISCORD_TOKEN", "SPOTIFY_PASSWORD", "SPOTIFY_TOKEN"];
+
+/// Longest activity name Discord accepts, in characters.
+const MAX_ACTIVITY_NAME_LENGTH: usize = 128;
+
+/// How the bot logs in to Spotify.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpotifyAuthMethod {
+    /// Username and password
+    #[default]
+    Password,
+    /// An OAuth access token with the `streaming` scope
+    Token,
+}
+
+/// How many gateway shards to run, from `SHARD_COUNT`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ShardCount {
+    Fixed(u64),
+    /// As many as Discord recommends for the number of guilds
+    Auto(AutoShards),
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoShards {
+    Auto,
+}
+
+/// How log lines are written.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with structured fields, for log aggregation
+    Json,
+}
+
+/// What to do when another device of the account takes over playback from the bot.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStolenAction {
+    /// Move playback back to the bot
+    Reclaim,
+    /// Stay in voice and wait for playback to come back, after telling the user
+    #[default]
+    Pause,
+    /// Leave voice after telling the user
+    Leave,
+}
+
+/// Which authorized user to follow when several are in voice channels.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MultiUserPolicy {
+    /// Stay with the user the bot followed first, until they leave voice
+    #[default]
+    FirstCome,
+    /// Follow whoever joined or moved to a voice channel last
+    MostRecent,
+    /// Follow the user listed first in `DISCORD_USER_ID` and `DISCORD_USER_IDS`
+    Priority,
+}
+
+/// How the currently playing track is shown in the bot's presence.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivityType {
+    /// "Listening to ..."
+    #[default]
+    Listening,
+    /// "Playing ..."
+    Playing,
+    /// "Streaming ...", linking to `ACTIVITY_URL`
+    Streaming,
+}
+
+impl ActivityType {
+    /// Build the presence activity for `name`.
+    /// Streaming without a valid URL is shown as playing, like Discord does.
+    pub fn activity(&self, name: String, url: Option<&str>) -> Activity {
+        match self {
+            ActivityType::Listening => Activity::listening(name),
+            ActivityType::Playing => Activity::playing(name),
+            ActivityType::Streaming => match url.filter(|url| reqwest::Url::parse(url).is_ok()) {
+                Some(url) => Activity::streaming(name, url),
+                None => Activity::playing(name),
+            },
+        }
+    }
+}
 
 #[derive(Deserialize, Clone)]
 pub struct Config {
@@ -10,27 +118,322 @@ pub struct Config {
     pub discord_token: String,
     #[serde(alias = "SPOTIFY_USERNAME")]
     pub spotify_username: String,
+    #[serde(alias = "SPOTIFY_AUTH_METHOD")]
+    #[serde(default)]
+    pub spotify_auth_method: SpotifyAuthMethod,
     #[serde(alias = "SPOTIFY_PASSWORD")]
-    pub spotify_password: String,
+    pub spotify_password: Option<String>,
+    #[serde(alias = "SPOTIFY_TOKEN")]
+    pub spotify_token: Option<String>,
     #[serde(alias = "DISCORD_USER_ID")]
     pub discord_user_id: u64,
+    #[serde(alias = "DISCORD_USER_IDS")]
+    #[serde(default, deserialize_with = "deserialize_id_list")]
+    pub discord_user_ids: Vec<u64>,
+    #[serde(alias = "MULTI_USER_POLICY")]
+    #[serde(default)]
+    pub multi_user_policy: MultiUserPolicy,
+    #[serde(alias = "GUILD_ID")]
+    pub guild_id: Option<u64>,
     #[serde(alias = "SPOTIFY_BOT_AUTOPLAY")]
     pub spotify_bot_autoplay: bool,
     #[serde(alias = "SPOTIFY_DEVICE_NAME")]
     #[serde(default = "default_spotify_device_name")]
     pub spotify_device_name: String,
+    #[serde(alias = "NORMALISATION")]
+    #[serde(default)]
+    pub normalisation: bool,
+    #[serde(alias = "TRACK_GAIN_ONLY")]
+    #[serde(default)]
+    pub track_gain_only: bool,
+    #[serde(alias = "SAMPLE_FORMAT")]
+    #[serde(default)]
+    pub sample_format: SampleFormat,
+    #[serde(alias = "OUTPUT_SAMPLE_RATE")]
+    #[serde(default = "default_output_sample_rate")]
+    pub output_sample_rate: u32,
+    #[serde(alias = "COMMAND_CHANNELS")]
+    #[serde(default, deserialize_with = "deserialize_id_list")]
+    pub command_channels: Vec<u64>,
+    #[serde(alias = "ANNOUNCE_CHANNEL_ID")]
+    pub announce_channel_id: Option<u64>,
+    #[serde(alias = "ANNOUNCE_VOICE")]
+    #[serde(default)]
+    pub announce_voice: bool,
+    #[serde(alias = "ANNOUNCE_RECONNECT")]
+    #[serde(default)]
+    pub announce_reconnect: bool,
+    #[serde(alias = "FILTER_EXPLICIT")]
+    #[serde(default)]
+    pub filter_explicit: bool,
+    #[serde(alias = "MAX_TRACK_MINUTES")]
+    pub max_track_minutes: Option<u64>,
+    #[serde(alias = "VOLUME_STEP")]
+    #[serde(default = "default_volume_step")]
+    pub volume_step: u8,
+    #[serde(alias = "DISCORD_PROXY")]
+    pub discord_proxy: Option<String>,
+    #[serde(alias = "METADATA_CACHE_SIZE")]
+    #[serde(default = "default_metadata_cache_size")]
+    pub metadata_cache_size: usize,
+    #[serde(alias = "METADATA_CACHE_TTL")]
+    #[serde(default = "default_metadata_cache_ttl")]
+    pub metadata_cache_ttl: u64,
+    #[serde(alias = "VOICE_BITRATE")]
+    pub voice_bitrate: Option<u64>,
+    #[serde(alias = "AUDIO_LATENCY_PROFILE")]
+    #[serde(default)]
+    pub audio_latency_profile: LatencyProfile,
+    #[serde(alias = "BUFFER_OVERRUN")]
+    #[serde(default)]
+    pub buffer_overrun: OverrunPolicy,
+    #[serde(alias = "PREBUFFER_MS")]
+    pub prebuffer_ms: Option<u64>,
+    #[serde(alias = "BUFFER_UNDERRUN")]
+    pub buffer_underrun: Option<UnderrunPolicy>,
+    #[serde(alias = "VOLUME_CTRL")]
+    #[serde(default)]
+    pub volume_ctrl: VolumeControl,
+    #[serde(alias = "ACTIVITY_TYPE")]
+    #[serde(default)]
+    pub activity_type: ActivityType,
+    #[serde(alias = "ACTIVITY_URL")]
+    pub activity_url: Option<String>,
+    #[serde(alias = "ACTIVITY_FORMAT")]
+    #[serde(default = "default_activity_format")]
+    pub activity_format: String,
+    #[serde(alias = "IDLE_PRESENCE")]
+    pub idle_presence: Option<String>,
+    #[serde(alias = "LEAVE_ON_STOP")]
+    #[serde(default = "default_leave_on_stop")]
+    pub leave_on_stop: bool,
+    #[serde(alias = "STOP_GRACE_SECS")]
+    #[serde(default)]
+    pub stop_grace_secs: u64,
+    #[serde(alias = "KEEP_VOICE_HANDLERS")]
+    #[serde(default = "default_keep_voice_handlers")]
+    pub keep_voice_handlers: bool,
+    #[serde(alias = "DUCK_LEVEL")]
+    pub duck_level: Option<u8>,
+    #[serde(alias = "DUCK_RELEASE_MS")]
+    #[serde(default = "default_duck_release_ms")]
+    pub duck_release_ms: u64,
+    #[serde(alias = "WARMUP")]
+    #[serde(default)]
+    pub warmup: bool,
+    #[serde(alias = "RADIO_MODE")]
+    #[serde(default)]
+    pub radio_mode: bool,
+    #[serde(alias = "INITIAL_CONTEXT")]
+    pub initial_context: Option<String>,
+    #[serde(alias = "ADAPTIVE_BITRATE")]
+    #[serde(default)]
+    pub adaptive_bitrate: bool,
+    #[serde(alias = "RECORD_PATH")]
+    pub record_path: Option<String>,
+    #[serde(alias = "STATS_PATH")]
+    pub stats_path: Option<String>,
+    #[serde(alias = "MAX_RECONNECT_ATTEMPTS")]
+    pub max_reconnect_attempts: Option<u32>,
+    #[serde(alias = "LOGIN_TIMEOUT_SECS")]
+    #[serde(default = "default_login_timeout_secs")]
+    pub login_timeout_secs: u64,
+    #[serde(alias = "EXIT_ON_RECONNECT_FAILURE")]
+    #[serde(default)]
+    pub exit_on_reconnect_failure: bool,
+    #[serde(alias = "VOICE_REJOIN")]
+    #[serde(default = "default_voice_rejoin")]
+    pub voice_rejoin: bool,
+    #[serde(alias = "SHARD_COUNT")]
+    pub shard_count: Option<ShardCount>,
+    #[serde(alias = "LOG_FORMAT")]
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(alias = "ON_SESSION_STOLEN")]
+    #[serde(default)]
+    pub on_session_stolen: SessionStolenAction,
 }
 
 fn default_spotify_device_name() -> String {
     "Aoede".to_string()
 }
 
+fn default_output_sample_rate() -> u32 {
+    songbird::constants::SAMPLE_RATE_RAW as u32
+}
+
+fn default_login_timeout_secs() -> u64 {
+    DEFAULT_LOGIN_TIMEOUT.as_secs()
+}
+
+fn default_volume_step() -> u8 {
+    10
+}
+
+/// Read a list of ids from a TOML array, or from a comma-separated environment variable.
+fn deserialize_id_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum IdList {
+        One(u64),
+        Many(Vec<u64>),
+        Text(String),
+    }
+
+    match IdList::deserialize(deserializer)? {
+        IdList::One(id) => Ok(vec![id]),
+        IdList::Many(ids) => Ok(ids),
+        IdList::Text(text) => text
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(|id| id.parse().map_err(de::Error::custom))
+            .collect(),
+    }
+}
+
+fn default_duck_release_ms() -> u64 {
+    1000
+}
+
+fn default_activity_format() -> String {
+    "{artist}: {track}".to_string()
+}
+
+fn default_keep_voice_handlers() -> bool {
+    true
+}
+
+fn default_leave_on_stop() -> bool {
+    true
+}
+
+fn default_voice_rejoin() -> bool {
+    true
+}
+
+fn default_metadata_cache_size() -> usize {
+    1000
+}
+
+fn default_metadata_cache_ttl() -> u64 {
+    // 1 day
+    24 * 60 * 60
+}
+
 impl Config {
+    #[allow(clippy::result_large_err)]
     pub fn new() -> Result<Self, Error> {
-        let config: Config = Figment::new()
+        let mut figment = Figment::new()
             .merge(Toml::file("config.toml"))
-            .merge(Env::raw())
-            .extract()?;
+            .merge(Env::raw());
+
+        // Docker and Kubernetes secrets are mounted as files, so read them
+        // from `<NAME>_FILE` instead of the environment when that's set
+        for name in SECRETS {
+            let Ok(path) = env::var(format!("{}_FILE", name)) else {
+                continue;
+            };
+
+            let secret = fs::read_to_string(&path)
+                .map_err(|error| format!("Could not read {}_FILE {}: {}", name, path, error))?;
+
+            figment = figment.merge(Serialized::default(
+                &name.to_lowercase(),
+                secret.trim_end_matches(['\r', '\n']),
+            ));
+        }
+
+        let config: Config = figment.extract()?;
         Ok(config)
     }
+
+    /// Take the settings that can change at runtime from `fresh`, keeping the rest.
+    /// Everything else needs a restart since it's baked into the session, player or client.
+    pub fn reloaded(&self, fresh: Config) -> Config {
+        Config {
+            discord_user_id: fresh.discord_user_id,
+            discord_user_ids: fresh.discord_user_ids,
+            multi_user_policy: fresh.multi_user_policy,
+            announce_channel_id: fresh.announce_channel_id,
+            announce_voice: fresh.announce_voice,
+            announce_reconnect: fresh.announce_reconnect,
+            command_channels: fresh.command_channels,
+            filter_explicit: fresh.filter_explicit,
+            max_track_minutes: fresh.max_track_minutes,
+            volume_step: fresh.volume_step,
+            activity_type: fresh.activity_type,
+            activity_url: fresh.activity_url,
+            activity_format: fresh.activity_format,
+            idle_presence: fresh.idle_presence,
+            leave_on_stop: fresh.leave_on_stop,
+            stop_grace_secs: fresh.stop_grace_secs,
+            keep_voice_handlers: fresh.keep_voice_handlers,
+            on_session_stolen: fresh.on_session_stolen,
+            voice_bitrate: fresh.voice_bitrate,
+            volume_ctrl: fresh.volume_ctrl,
+            audio_latency_profile: fresh.audio_latency_profile,
+            ..self.clone()
+        }
+    }
+
+    /// Users the bot follows and takes commands from, `DISCORD_USER_ID` first.
+    /// In priority order for `MULTI_USER_POLICY=priority`.
+    pub fn user_ids(&self) -> Vec<UserId> {
+        let mut user_ids = vec![UserId(self.discord_user_id)];
+
+        for user_id in self.discord_user_ids.iter().map(|id| UserId(*id)) {
+            if !user_ids.contains(&user_id) {
+                user_ids.push(user_id);
+            }
+        }
+
+        user_ids
+    }
+
+    /// Status text for a track, from `ACTIVITY_FORMAT`. Cut to the length Discord
+    /// accepts, it drops the whole activity if the name is longer.
+    pub fn activity_name(&self, artist: &str, track: &str, album: &str) -> String {
+        let name = self
+            .activity_format
+            .replace("{artist}", artist)
+            .replace("{track}", track)
+            .replace("{album}", album);
+
+        if name.chars().count() <= MAX_ACTIVITY_NAME_LENGTH {
+            return name;
+        }
+
+        let mut name: String = name.chars().take(MAX_ACTIVITY_NAME_LENGTH - 1).collect();
+        name.push('…');
+        name
+    }
+
+    /// Presence to show while nothing is playing, none if `IDLE_PRESENCE` is empty.
+    pub fn idle_activity(&self) -> Option<Activity> {
+        self.idle_presence
+            .as_ref()
+            .filter(|presence| !presence.trim().is_empty())
+            .map(Activity::playing)
+    }
+
+    /// Build the Spotify credentials for the configured auth method.
+    pub fn spotify_credentials(&self) -> Result<Credentials, String> {
+        let username = self.spotify_username.clone();
+
+        match self.spotify_auth_method {
+            SpotifyAuthMethod::Password => match self.spotify_password.as_ref() {
+                Some(password) => Ok(Credentials::with_password(username, password)),
+                None => Err("Missing field: 'SPOTIFY_PASSWORD'".to_string()),
+            },
+            SpotifyAuthMethod::Token => match self.spotify_token.as_ref() {
+                Some(token) => Ok(Credentials {
+                    username,
+                    auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+                    auth_data: token.as_bytes().to_vec(),
+                }),
+                None => Err("Missing field: 'SPOTIFY_TOKEN'".to_string()),
+            },
+        }
+    }
 }
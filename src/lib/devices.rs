@@ -0,0 +1,263 @@
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use librespot::protocol::spirc::{DeviceState, Frame, MessageType, PlayStatus, State, TrackRef};
+
+use protobuf::{Message, RepeatedField};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::time::{sleep, Duration};
+
+/// How long to wait for other devices to answer a hello before listing them.
+const HELLO_RESPONSE_WINDOW: Duration = Duration::from_millis(1500);
+
+#[derive(Clone, Debug)]
+pub struct ConnectDevice {
+    pub ident: String,
+    pub name: String,
+    pub is_active: bool,
+    pub is_bot: bool,
+    state: Option<State>,
+}
+
+/// Watches the Spotify Connect remote channel of the account and keeps track of
+/// the devices announcing themselves on it, including the bot itself.
+#[derive(Clone)]
+pub struct DeviceTracker {
+    session: Session,
+    ident: String,
+    uri: String,
+    devices: Arc<Mutex<HashMap<String, ConnectDevice>>>,
+}
+
+impl DeviceTracker {
+    pub fn new(session: Session) -> DeviceTracker {
+        let uri = format!(
+            "hm://remote/user/{}/",
+            form_urlencoded::byte_serialize(session.username().as_bytes()).collect::<String>()
+        );
+
+        let tracker = DeviceTracker {
+            // Use our own ident so the bot's Spirc doesn't treat frames addressed
+            // to the tracker as its own
+            ident: format!("{}-tracker", session.device_id()),
+            session,
+            uri,
+            devices: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        let cloned_tracker = tracker.clone();
+        tokio::spawn(async move {
+            cloned_tracker.watch().await;
+        });
+
+        tracker
+    }
+
+    async fn watch(&self) {
+        let mut subscription = match self.session.mercury().subscribe(self.uri.clone()).await {
+            Ok(subscription) => subscription,
+            Err(error) => {
+                tracing::warn!("Could not subscribe to Connect devices: {:?}", error);
+                return;
+            }
+        };
+
+        while let Some(response) = subscription.recv().await {
+            let frame = match response
+                .payload
+                .first()
+                .map(|data| Frame::parse_from_bytes(data))
+            {
+                Some(Ok(frame)) => frame,
+                _ => continue,
+            };
+
+            if frame.get_ident() == self.ident {
+                continue;
+            }
+
+            let mut devices = self.devices.lock().unwrap();
+
+            match frame.get_typ() {
+                MessageType::kMessageTypeGoodbye => {
+                    devices.remove(frame.get_ident());
+                }
+                MessageType::kMessageTypeHello | MessageType::kMessageTypeNotify => {
+                    let device_state = frame.get_device_state();
+
+                    let state = if device_state.get_is_active() && frame.has_state() {
+                        Some(frame.get_state().clone())
+                    } else {
+                        None
+                    };
+
+                    devices.insert(
+                        frame.get_ident().to_string(),
+                        ConnectDevice {
+                            ident: frame.get_ident().to_string(),
+                            name: device_state.get_name().to_string(),
+                            is_active: device_state.get_is_active(),
+                            is_bot: frame.get_ident() == self.session.device_id(),
+                            state,
+                        },
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Asks every device on the account to announce itself, then returns all known devices.
+    pub async fn devices(&self) -> Vec<ConnectDevice> {
+        self.send(self.frame(MessageType::kMessageTypeHello));
+
+        sleep(HELLO_RESPONSE_WINDOW).await;
+
+        let mut devices: Vec<ConnectDevice> =
+            self.devices.lock().unwrap().values().cloned().collect();
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        devices
+    }
+
+    /// Whether a device other than the bot is playing, as of its last announcement.
+    pub fn other_device_active(&self) -> bool {
+        self.active_device().is_some()
+    }
+
+    /// The device other than the bot that is playing, as of its last announcement.
+    pub fn active_device(&self) -> Option<ConnectDevice> {
+        self.devices
+            .lock()
+            .unwrap()
+            .values()
+            .find(|device| device.is_active && !device.is_bot)
+            .cloned()
+    }
+
+    /// Moves playback from `device` to the bot.
+    pub fn reclaim(&self, device: &ConnectDevice) -> Result<(), String> {
+        let state = device
+            .state
+            .clone()
+            .ok_or_else(|| format!("{} didn't announce what it's playing", device.name))?;
+
+        self.load(self.session.device_id(), state);
+
+        Ok(())
+    }
+
+    /// Moves playback from the currently active device to `target`.
+    pub fn transfer(&self, target: &ConnectDevice) -> Result<(), String> {
+        if target.is_active {
+            return Err(format!("{} is already playing", target.name));
+        }
+
+        let state = self
+            .devices
+            .lock()
+            .unwrap()
+            .values()
+            .find_map(|device| device.state.clone())
+            .ok_or_else(|| "No device is currently playing".to_string())?;
+
+        self.load(&target.ident, state);
+
+        Ok(())
+    }
+
+    /// Makes the bot play `tracks` from the first one, as the context `context_uri`.
+    pub fn play(&self, context_uri: String, tracks: &[SpotifyId]) {
+        let tracks = tracks
+            .iter()
+            .map(|id| {
+                let mut track = TrackRef::new();
+                track.set_gid(id.to_raw().to_vec());
+                track
+            })
+            .collect();
+
+        let mut state = State::new();
+        state.set_context_uri(context_uri);
+        state.set_track(RepeatedField::from_vec(tracks));
+        state.set_playing_track_index(0);
+        state.set_position_ms(0);
+        state.set_position_measured_at(self.now_ms() as u64);
+        state.set_status(PlayStatus::kPlayStatusPlay);
+
+        self.load(self.session.device_id(), state);
+    }
+
+    /// Makes the bot jump to `position_ms` in the current track.
+    pub fn seek(&self, position_ms: u32) {
+        let mut frame = self.frame(MessageType::kMessageTypeSeek);
+        frame
+            .mut_recipient()
+            .push(self.session.device_id().to_string());
+        frame.set_position(position_ms);
+
+        self.send(frame);
+    }
+
+    /// Playback state of the bot, if it is the active device.
+    pub fn bot_state(&self) -> Option<State> {
+        self.devices
+            .lock()
+            .unwrap()
+            .values()
+            .find(|device| device.is_bot)
+            .and_then(|device| device.state.clone())
+    }
+
+    /// Makes the device `ident` play `state`, continuing where it was measured.
+    pub fn load(&self, ident: &str, mut state: State) {
+        // The position is only updated on state changes, so account for the
+        // time that has passed since it was measured
+        if state.get_status() == PlayStatus::kPlayStatusPlay {
+            let elapsed = self.now_ms() - state.get_position_measured_at() as i64;
+            if elapsed > 0 {
+                state.set_position_ms(state.get_position_ms() + elapsed as u32);
+            }
+        }
+
+        let mut frame = self.frame(MessageType::kMessageTypeLoad);
+        frame.mut_recipient().push(ident.to_string());
+        frame.set_state(state);
+
+        self.send(frame);
+    }
+
+    fn frame(&self, typ: MessageType) -> Frame {
+        let mut device_state = DeviceState::new();
+        device_state.set_is_active(false);
+        device_state.set_can_play(false);
+
+        let mut frame = Frame::new();
+        frame.set_version(1);
+        frame.set_protocol_version("2.0.0".to_string());
+        frame.set_ident(self.ident.clone());
+        frame.set_seq_nr(1);
+        frame.set_typ(typ);
+        frame.set_device_state(device_state);
+        frame.set_state_update_id(self.now_ms());
+        frame
+    }
+
+    fn send(&self, frame: Frame) {
+        self.session
+            .mercury()
+            .sender(self.uri.clone())
+            .send(frame.write_to_bytes().unwrap());
+    }
+
+    fn now_ms(&self) -> i64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+
+        now + 1000 * self.session.time_delta()
+    }
+}
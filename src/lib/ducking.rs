@@ -0,0 +1,114 @@
+use super::player::FadingGain;
+
+use serenity::async_trait;
+use serenity::cache::Cache;
+use serenity::model::id::UserId;
+
+use songbird::events::{Event, EventContext, EventHandler as VoiceEventHandler};
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use tokio::time::{sleep, Duration};
+
+#[derive(Default)]
+struct DuckState {
+    // Sources currently transmitting
+    speaking: HashSet<u32>,
+    // Sources of bots, which don't duck the music
+    ignored: HashSet<u32>,
+    // Bumped whenever someone starts speaking, so a release only applies if nobody spoke since
+    generation: u64,
+}
+
+/// Lowers the music while members of the voice channel speak, and brings it back
+/// `release` after the last one stopped. Register it for `CoreEvent::SpeakingStateUpdate`
+/// (to tell bots apart) and `CoreEvent::SpeakingUpdate`.
+#[derive(Clone)]
+pub struct Ducker {
+    gain: Arc<FadingGain>,
+    /// Gain while someone speaks
+    level: f32,
+    release: Duration,
+    cache: Arc<Cache>,
+    state: Arc<Mutex<DuckState>>,
+}
+
+impl Ducker {
+    /// Duck `gain` to `level_percent` of the volume while someone speaks.
+    pub fn new(
+        gain: Arc<FadingGain>,
+        level_percent: u8,
+        release: Duration,
+        cache: Arc<Cache>,
+    ) -> Ducker {
+        Ducker {
+            gain,
+            level: level_percent.min(100) as f32 / 100.0,
+            release,
+            cache,
+            state: Arc::new(Mutex::new(DuckState::default())),
+        }
+    }
+
+    fn is_bot(&self, user_id: UserId) -> bool {
+        // Users missing from the cache are most likely people who just joined
+        self.cache.user(user_id).is_some_and(|user| user.bot)
+    }
+
+    fn speaking(&self, ssrc: u32, speaking: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.ignored.contains(&ssrc) {
+            return;
+        }
+
+        if speaking {
+            if state.speaking.is_empty() {
+                tracing::debug!("Someone started speaking, ducking the music");
+            }
+            state.speaking.insert(ssrc);
+            state.generation += 1;
+            self.gain.fade_to(self.level);
+            return;
+        }
+
+        if !state.speaking.remove(&ssrc) || !state.speaking.is_empty() {
+            return;
+        }
+
+        let generation = state.generation;
+        let ducker = self.clone();
+
+        tokio::spawn(async move {
+            sleep(ducker.release).await;
+
+            let state = ducker.state.lock().unwrap();
+            if state.generation == generation && state.speaking.is_empty() {
+                tracing::debug!("Nobody is speaking anymore, restoring the music");
+                ducker.gain.fade_to(1.0);
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl VoiceEventHandler for Ducker {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        match ctx {
+            EventContext::SpeakingStateUpdate(speaking) => {
+                let user_id = speaking.user_id?;
+
+                if self.is_bot(UserId(user_id.0)) {
+                    // In case it already ducked the music before it was recognized
+                    self.speaking(speaking.ssrc, false);
+                    self.state.lock().unwrap().ignored.insert(speaking.ssrc);
+                }
+            }
+            EventContext::SpeakingUpdate(update) => self.speaking(update.ssrc, update.speaking),
+            _ => {}
+        }
+
+        None
+    }
+}
@@ -0,0 +1,138 @@
+use librespot::core::spotify_id::SpotifyId;
+use librespot::playback::player::PlayerEvent;
+
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+/// Events a subscriber can fall behind by before it starts missing some.
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+
+/// What happens to playback, for embedders that want to build on the player
+/// without handling librespot's `PlayerEvent`s themselves.
+///
+/// Events are delivered over a `tokio::sync::broadcast` channel, see
+/// `SpotifyPlayer::subscribe`. Delivery is lossy: a subscriber more than
+/// `EVENT_CHANNEL_CAPACITY` events behind gets `RecvError::Lagged` with the number
+/// of events it missed, then continues with the oldest one still buffered. Events
+/// sent while nobody is subscribed are dropped.
+// Only read by embedders, the bot handles librespot's events directly
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlaybackEvent {
+    /// Another track started, or was loaded paused
+    TrackChanged {
+        track_id: SpotifyId,
+        duration_ms: u32,
+    },
+    Paused {
+        track_id: SpotifyId,
+        position_ms: u32,
+    },
+    /// Playback continued after `Paused`
+    Resumed {
+        track_id: SpotifyId,
+        position_ms: u32,
+    },
+    /// Playback stopped, e.g. at the end of the context or when another device took over
+    Stopped { track_id: SpotifyId },
+    /// The volume changed in Spotify or through a command, from 0 to 65535
+    VolumeChanged { volume: u16 },
+}
+
+#[derive(Default)]
+struct PublishedState {
+    track_id: Option<SpotifyId>,
+    paused: bool,
+}
+
+/// Turns librespot's events into `PlaybackEvent`s for the subscribers. librespot
+/// reports playing again on seeks and underruns too, this only passes on changes.
+pub struct PlaybackEvents {
+    sender: broadcast::Sender<PlaybackEvent>,
+    state: Mutex<PublishedState>,
+}
+
+impl PlaybackEvents {
+    pub fn new() -> PlaybackEvents {
+        PlaybackEvents {
+            sender: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            state: Mutex::new(PublishedState::default()),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PlaybackEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publish what `event` changed, if anything.
+    pub fn handle(&self, event: &PlayerEvent) {
+        let mut state = self.state.lock().unwrap();
+
+        match *event {
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            } => {
+                if state.track_id != Some(track_id) {
+                    state.track_id = Some(track_id);
+                    self.send(PlaybackEvent::TrackChanged {
+                        track_id,
+                        duration_ms,
+                    });
+                } else if state.paused {
+                    self.send(PlaybackEvent::Resumed {
+                        track_id,
+                        position_ms,
+                    });
+                }
+                state.paused = false;
+            }
+            PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            } => {
+                if state.track_id != Some(track_id) {
+                    state.track_id = Some(track_id);
+                    self.send(PlaybackEvent::TrackChanged {
+                        track_id,
+                        duration_ms,
+                    });
+                }
+                if !state.paused {
+                    self.send(PlaybackEvent::Paused {
+                        track_id,
+                        position_ms,
+                    });
+                }
+                state.paused = true;
+            }
+            PlayerEvent::Stopped { track_id, .. } => {
+                *state = PublishedState::default();
+                self.send(PlaybackEvent::Stopped { track_id });
+            }
+            PlayerEvent::VolumeSet { volume } => self.volume_changed(volume),
+            _ => {}
+        }
+    }
+
+    /// The volume was changed to `volume` without librespot noticing, e.g. by a command.
+    pub fn volume_changed(&self, volume: u16) {
+        self.send(PlaybackEvent::VolumeChanged { volume });
+    }
+
+    fn send(&self, event: PlaybackEvent) {
+        // Fails only without subscribers, who wouldn't have seen it anyway
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for PlaybackEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
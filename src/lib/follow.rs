@@ -0,0 +1,100 @@
+use super::config::MultiUserPolicy;
+
+use serenity::model::id::UserId;
+use serenity::prelude::TypeMapKey;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct FollowState {
+    followed: Option<UserId>,
+    // When each authorized user last joined or moved, as a sequence number.
+    // Users who were already in voice when the bot started have none
+    joined: HashMap<UserId, u64>,
+    sequence: u64,
+}
+
+/// Keeps track of which authorized user the bot follows into voice, so with several
+/// of them in different channels it sticks to one as `MULTI_USER_POLICY` says.
+#[derive(Default)]
+pub struct Follower {
+    state: Mutex<FollowState>,
+}
+
+pub struct FollowerKey;
+
+impl TypeMapKey for FollowerKey {
+    type Value = Arc<Follower>;
+}
+
+impl Follower {
+    pub fn followed(&self) -> Option<UserId> {
+        self.state.lock().unwrap().followed
+    }
+
+    pub fn follow(&self, user_id: Option<UserId>) {
+        self.state.lock().unwrap().followed = user_id;
+    }
+
+    /// `user_id` joined or moved to a voice channel.
+    pub fn joined(&self, user_id: UserId) {
+        let mut state = self.state.lock().unwrap();
+        state.sequence += 1;
+        let sequence = state.sequence;
+        state.joined.insert(user_id, sequence);
+    }
+
+    /// `user_id` left voice.
+    pub fn left(&self, user_id: UserId) {
+        self.state.lock().unwrap().joined.remove(&user_id);
+    }
+
+    /// Whether to follow `user_id`, who just joined or moved, instead of the followed user.
+    /// `user_ids` are the authorized users in priority order.
+    pub fn prefers(&self, policy: MultiUserPolicy, user_ids: &[UserId], user_id: UserId) -> bool {
+        let Some(followed) = self.followed() else {
+            return true;
+        };
+
+        match policy {
+            MultiUserPolicy::FirstCome => followed == user_id,
+            MultiUserPolicy::MostRecent => true,
+            MultiUserPolicy::Priority => {
+                priority(user_ids, user_id) <= priority(user_ids, followed)
+            }
+        }
+    }
+
+    /// The user to follow among `candidates`, the authorized users currently in voice.
+    /// Ties, e.g. between users who were in voice before the bot started, go to the
+    /// one listed first so the choice doesn't depend on the order of the cache.
+    pub fn pick(
+        &self,
+        policy: MultiUserPolicy,
+        user_ids: &[UserId],
+        candidates: &[UserId],
+    ) -> Option<UserId> {
+        let state = self.state.lock().unwrap();
+        let joined = |user_id: &UserId| state.joined.get(user_id).copied();
+
+        candidates.iter().copied().min_by_key(|user_id| {
+            let order = match policy {
+                MultiUserPolicy::FirstCome => joined(user_id).unwrap_or(0),
+                // Joined later sorts first
+                MultiUserPolicy::MostRecent => u64::MAX - joined(user_id).unwrap_or(0),
+                MultiUserPolicy::Priority => 0,
+            };
+
+            (order, priority(user_ids, *user_id))
+        })
+    }
+}
+
+/// Position of `user_id` in `user_ids`, lower is preferred.
+fn priority(user_ids: &[UserId], user_id: UserId) -> usize {
+    user_ids
+        .iter()
+        .position(|id| *id == user_id)
+        .unwrap_or(usize::MAX)
+}
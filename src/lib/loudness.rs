@@ -0,0 +1,89 @@
+use librespot::audio::{AudioDecrypt, AudioFile};
+use librespot::core::session::Session;
+use librespot::metadata::{FileFormat, Track};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Spotify stores the normalisation data at this offset of the decrypted audio file.
+const NORMALISATION_HEADER_OFFSET: u64 = 144;
+
+/// Spotify's gain values bring tracks to this loudness.
+const REFERENCE_LOUDNESS_LUFS: f64 = -14.0;
+
+/// Formats carrying the normalisation header, in order of preference.
+const FORMATS: [FileFormat; 3] = [
+    FileFormat::OGG_VORBIS_320,
+    FileFormat::OGG_VORBIS_160,
+    FileFormat::OGG_VORBIS_96,
+];
+
+/// The ReplayGain-style data librespot uses for normalisation.
+#[derive(Clone, Copy, Debug)]
+pub struct Loudness {
+    pub track_gain_db: f32,
+    pub track_peak: f32,
+    pub album_gain_db: f32,
+    pub album_peak: f32,
+}
+
+impl Loudness {
+    /// Read the normalisation data of `track` from its audio file.
+    /// librespot doesn't expose what it read while loading the track, so this
+    /// fetches the start of the file again (or reads it from the audio cache).
+    pub async fn fetch(session: &Session, track: &Track) -> Option<Loudness> {
+        let file_id = *FORMATS.iter().find_map(|format| track.files.get(format))?;
+
+        // Only the header is needed, the bitrate hint just sizes the first request
+        let file = match AudioFile::open(session, file_id, 40 * 1024, true).await {
+            Ok(file) => file,
+            Err(error) => {
+                tracing::warn!("Could not open audio file of {:?}: {:?}", track.id, error);
+                return None;
+            }
+        };
+
+        let key = match session.audio_key().request(track.id, file_id).await {
+            Ok(key) => key,
+            Err(error) => {
+                tracing::warn!("Could not get audio key of {:?}: {:?}", track.id, error);
+                return None;
+            }
+        };
+
+        let controller = file.get_stream_loader_controller();
+
+        // Reading a streamed file blocks until the data arrived
+        let result =
+            tokio::task::spawn_blocking(move || Loudness::parse(&mut AudioDecrypt::new(key, file)))
+                .await;
+
+        // Don't download the rest of the file
+        controller.close();
+
+        match result {
+            Ok(Ok(loudness)) => Some(loudness),
+            _ => {
+                tracing::warn!("Could not read normalisation data of {:?}", track.id);
+                None
+            }
+        }
+    }
+
+    fn parse<T: Read + Seek>(file: &mut T) -> io::Result<Loudness> {
+        file.seek(SeekFrom::Start(NORMALISATION_HEADER_OFFSET))?;
+
+        Ok(Loudness {
+            track_gain_db: file.read_f32::<LittleEndian>()?,
+            track_peak: file.read_f32::<LittleEndian>()?,
+            album_gain_db: file.read_f32::<LittleEndian>()?,
+            album_peak: file.read_f32::<LittleEndian>()?,
+        })
+    }
+
+    /// Approximate integrated loudness of the track.
+    pub fn track_lufs(&self) -> f64 {
+        REFERENCE_LOUDNESS_LUFS - self.track_gain_db as f64
+    }
+}
@@ -0,0 +1,141 @@
+use librespot::core::keymaster;
+use librespot::core::mercury::MercuryError;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+
+use serde::Deserialize;
+
+use std::fmt;
+
+/// Client id of librespot, which may request tokens for the Spotify APIs.
+pub const KEYMASTER_CLIENT_ID: &str = "65b708073fc0480ea92a077233ca87bd";
+
+const LYRICS_URL: &str = "https://spclient.wg.spotify.com/color-lyrics/v2/track";
+
+#[derive(Debug)]
+pub enum LyricsError {
+    /// No access token for the lyrics API
+    Token(MercuryError),
+    Request(reqwest::Error),
+    /// The response wasn't in the expected format
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for LyricsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LyricsError::Token(_) => write!(f, "could not get an access token"),
+            LyricsError::Request(error) => write!(f, "request failed: {}", error),
+            LyricsError::Parse(error) => write!(f, "unexpected response: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for LyricsError {}
+
+#[derive(Debug, Clone)]
+pub struct LyricsLine {
+    /// Start of the line in the track, `None` if the lyrics aren't synced
+    pub start_ms: Option<u32>,
+    pub words: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Lyrics {
+    pub lines: Vec<LyricsLine>,
+    /// Who provides the lyrics, e.g. Musixmatch
+    pub provider: String,
+}
+
+#[derive(Deserialize)]
+struct LyricsResponse {
+    lyrics: LyricsBody,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LyricsBody {
+    sync_type: String,
+    lines: Vec<LineBody>,
+    #[serde(default)]
+    provider_display_name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LineBody {
+    // Milliseconds, as a string
+    start_time_ms: String,
+    words: String,
+}
+
+impl Lyrics {
+    /// Fetch the lyrics of `track_id` from the lyrics API of the Spotify clients.
+    /// `Ok(None)` if Spotify has no lyrics for the track.
+    pub async fn fetch(
+        session: &Session,
+        track_id: SpotifyId,
+    ) -> Result<Option<Lyrics>, LyricsError> {
+        let Ok(id) = track_id.to_base62() else {
+            return Ok(None);
+        };
+
+        let token = keymaster::get_token(session, KEYMASTER_CLIENT_ID, "streaming")
+            .await
+            .map_err(LyricsError::Token)?;
+
+        let response = reqwest::Client::new()
+            .get(format!(
+                "{}/{}?format=json&market=from_token",
+                LYRICS_URL, id
+            ))
+            .bearer_auth(token.access_token)
+            // The endpoint only answers requests that look like they come from a client
+            .header("App-Platform", "WebPlayer")
+            .send()
+            .await
+            .map_err(LyricsError::Request)?;
+
+        // Tracks without lyrics are a 404
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let body = response
+            .error_for_status()
+            .map_err(LyricsError::Request)?
+            .bytes()
+            .await
+            .map_err(LyricsError::Request)?;
+
+        let response: LyricsResponse = serde_json::from_slice(&body).map_err(LyricsError::Parse)?;
+        let synced = response.lyrics.sync_type == "LINE_SYNCED";
+
+        let lines: Vec<LyricsLine> = response
+            .lyrics
+            .lines
+            .into_iter()
+            .map(|line| LyricsLine {
+                start_ms: line.start_time_ms.parse().ok().filter(|_| synced),
+                words: line.words,
+            })
+            .collect();
+
+        if lines.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Lyrics {
+            lines,
+            provider: response.lyrics.provider_display_name,
+        }))
+    }
+
+    /// Index of the line being sung at `position_ms`, if the lyrics are synced.
+    pub fn current_line(&self, position_ms: u32) -> Option<usize> {
+        self.lines.iter().rposition(|line| {
+            line.start_ms
+                .is_some_and(|start_ms| start_ms <= position_ms)
+        })
+    }
+}
@@ -0,0 +1,322 @@
+use librespot::core::keymaster;
+use librespot::core::mercury::MercuryError;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::{FileId, SpotifyId, SpotifyIdError};
+use librespot::metadata::{Album, Artist, Metadata, Track};
+use librespot::protocol;
+use librespot::protocol::metadata::Image_Size;
+
+use serenity::prelude::TypeMapKey;
+
+use super::lyrics::KEYMASTER_CLIENT_ID;
+
+use std::collections::HashMap;
+use std::string::FromUtf8Error;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout, Duration};
+
+const METADATA_ATTEMPTS: u32 = 3;
+const METADATA_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Give up on the token request checking the session after this long, see `MetadataCache::get`.
+const SESSION_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fetch metadata, retrying with exponential backoff on transient Mercury failures.
+pub async fn get<T: Metadata>(session: &Session, id: SpotifyId) -> Result<T, MercuryError> {
+    let mut backoff = METADATA_INITIAL_BACKOFF;
+
+    for attempt in 1..METADATA_ATTEMPTS {
+        match T::get(session, id).await {
+            Ok(metadata) => return Ok(metadata),
+            Err(_) => {
+                tracing::debug!(
+                    "Fetching metadata for {:?} failed (attempt {}/{}), retrying in {:?}",
+                    id,
+                    attempt,
+                    METADATA_ATTEMPTS,
+                    backoff
+                );
+                sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+
+    T::get(session, id).await.inspect_err(|_| {
+        tracing::warn!(
+            "Fetching metadata for {:?} failed after {} attempts",
+            id,
+            METADATA_ATTEMPTS
+        );
+    })
+}
+
+/// Whether `session` still answers a token request, a small Mercury round trip.
+async fn session_works(session: &Session) -> bool {
+    let token = keymaster::get_token(session, KEYMASTER_CLIENT_ID, "streaming");
+    matches!(timeout(SESSION_PROBE_TIMEOUT, token).await, Ok(Ok(_)))
+}
+
+/// Track metadata that librespot's `Track` doesn't expose.
+#[derive(Debug, Clone)]
+pub struct TrackDetails {
+    pub explicit: bool,
+}
+
+impl Metadata for TrackDetails {
+    type Message = protocol::metadata::Track;
+
+    fn request_url(id: SpotifyId) -> Result<String, FromUtf8Error> {
+        let id = id.to_base16()?;
+        Ok(format!("hm://metadata/3/track/{}", id))
+    }
+
+    fn parse(msg: &Self::Message, _: &Session) -> Result<Self, SpotifyIdError> {
+        Ok(TrackDetails {
+            explicit: msg.get_explicit(),
+        })
+    }
+}
+
+/// Album metadata that librespot's `Album` doesn't expose.
+#[derive(Debug, Clone)]
+pub struct AlbumDetails {
+    /// The highest resolution cover, librespot's `Album::covers` loses the sizes
+    pub largest_cover: Option<FileId>,
+}
+
+impl Metadata for AlbumDetails {
+    type Message = protocol::metadata::Album;
+
+    fn request_url(id: SpotifyId) -> Result<String, FromUtf8Error> {
+        let id = id.to_base16()?;
+        Ok(format!("hm://metadata/3/album/{}", id))
+    }
+
+    fn parse(msg: &Self::Message, _: &Session) -> Result<Self, SpotifyIdError> {
+        let largest_cover = msg
+            .get_cover_group()
+            .get_image()
+            .iter()
+            .filter(|image| image.get_file_id().len() == 20)
+            .max_by_key(|image| {
+                // Not every image has its dimensions, so fall back to the size class
+                let size = match image.get_size() {
+                    Image_Size::SMALL => 1,
+                    Image_Size::DEFAULT => 2,
+                    Image_Size::LARGE => 3,
+                    Image_Size::XLARGE => 4,
+                };
+                (image.get_width(), size)
+            })
+            .map(|image| {
+                let mut file_id = [0u8; 20];
+                file_id.clone_from_slice(image.get_file_id());
+                FileId(file_id)
+            });
+
+        Ok(AlbumDetails { largest_cover })
+    }
+}
+
+struct CacheEntry<T> {
+    value: T,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+/// A size-capped cache evicting the least recently used entry, whose entries expire after `ttl`.
+pub struct LruCache<T> {
+    entries: Mutex<(u64, HashMap<SpotifyId, CacheEntry<T>>)>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl<T: Clone> LruCache<T> {
+    pub fn new(capacity: usize, ttl: Duration) -> LruCache<T> {
+        LruCache {
+            entries: Mutex::new((0, HashMap::new())),
+            capacity,
+            ttl,
+        }
+    }
+
+    pub fn get(&self, id: SpotifyId) -> Option<T> {
+        let mut guard = self.entries.lock().unwrap();
+        let (clock, entries) = &mut *guard;
+
+        match entries.get_mut(&id) {
+            Some(entry) if entry.inserted_at.elapsed() < self.ttl => {
+                *clock += 1;
+                entry.last_used = *clock;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                entries.remove(&id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, id: SpotifyId, value: T) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut guard = self.entries.lock().unwrap();
+        let (clock, entries) = &mut *guard;
+
+        if !entries.contains_key(&id) && entries.len() >= self.capacity {
+            let ttl = self.ttl;
+            entries.retain(|_, entry| entry.inserted_at.elapsed() < ttl);
+
+            if entries.len() >= self.capacity {
+                if let Some(oldest) = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(id, _)| *id)
+                {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+
+        *clock += 1;
+        entries.insert(
+            id,
+            CacheEntry {
+                value,
+                inserted_at: Instant::now(),
+                last_used: *clock,
+            },
+        );
+    }
+}
+
+/// Metadata types kept in a [`MetadataCache`].
+pub trait Cacheable: Metadata + Clone {
+    fn cache(caches: &MetadataCache) -> &LruCache<Self>;
+}
+
+/// Caches metadata lookups so the event loop doesn't hit Spotify for every event.
+pub struct MetadataCache {
+    // Replaced when the bot logs in to Spotify again
+    session: Mutex<Session>,
+    tracks: LruCache<Track>,
+    track_details: LruCache<TrackDetails>,
+    artists: LruCache<Artist>,
+    albums: LruCache<Album>,
+    album_details: LruCache<AlbumDetails>,
+    // Woken when lookups find the session broken, see `session_broken`
+    broken_session: Notify,
+}
+
+pub struct MetadataCacheKey;
+
+impl TypeMapKey for MetadataCacheKey {
+    type Value = Arc<MetadataCache>;
+}
+
+impl MetadataCache {
+    pub fn new(session: Session, capacity: usize, ttl: Duration) -> MetadataCache {
+        MetadataCache {
+            session: Mutex::new(session),
+            tracks: LruCache::new(capacity, ttl),
+            track_details: LruCache::new(capacity, ttl),
+            artists: LruCache::new(capacity, ttl),
+            albums: LruCache::new(capacity, ttl),
+            album_details: LruCache::new(capacity, ttl),
+            broken_session: Notify::new(),
+        }
+    }
+
+    pub async fn get<T: Cacheable>(&self, id: SpotifyId) -> Result<T, MercuryError> {
+        if let Some(value) = T::cache(self).get(id) {
+            return Ok(value);
+        }
+
+        let session = self.session.lock().unwrap().clone();
+
+        let value: T = match get(&session, id).await {
+            Ok(value) => value,
+            Err(error) => {
+                if session.is_invalid() {
+                    tracing::warn!("Metadata lookups fail because the Spotify session was closed");
+                } else if !session_works(&session).await {
+                    // MercuryError doesn't say why, so only a failing token request
+                    // tells an expired session apart from e.g. a missing track
+                    tracing::warn!(
+                        "Metadata lookups and token requests fail, logging in to Spotify again"
+                    );
+                    session.shutdown();
+                    self.broken_session.notify_one();
+                }
+                return Err(error);
+            }
+        };
+        T::cache(self).insert(id, value.clone());
+
+        Ok(value)
+    }
+
+    /// Wait until a lookup closed the session because it stopped working, to log in
+    /// again right away instead of at the next check.
+    pub async fn session_broken(&self) {
+        self.broken_session.notified().await;
+    }
+
+    /// Look up metadata on `session` from now on, after logging in again.
+    pub fn set_session(&self, session: Session) {
+        *self.session.lock().unwrap() = session;
+    }
+
+    /// URL of the album art of `track`, if its album has any.
+    pub async fn cover_url(&self, track: &Track) -> Option<String> {
+        let album: Album = self.get(track.album).await.ok()?;
+        let file_id = album.covers.first()?.to_base16().ok()?;
+
+        Some(format!("https://i.scdn.co/image/{}", file_id))
+    }
+
+    /// URL of the highest resolution album art of `track`, if its album has any.
+    pub async fn largest_cover_url(&self, track: &Track) -> Option<String> {
+        let album: AlbumDetails = self.get(track.album).await.ok()?;
+        let file_id = album.largest_cover?.to_base16().ok()?;
+
+        Some(format!("https://i.scdn.co/image/{}", file_id))
+    }
+}
+
+impl Cacheable for Track {
+    fn cache(caches: &MetadataCache) -> &LruCache<Self> {
+        &caches.tracks
+    }
+}
+
+impl Cacheable for TrackDetails {
+    fn cache(caches: &MetadataCache) -> &LruCache<Self> {
+        &caches.track_details
+    }
+}
+
+impl Cacheable for Artist {
+    fn cache(caches: &MetadataCache) -> &LruCache<Self> {
+        &caches.artists
+    }
+}
+
+impl Cacheable for Album {
+    fn cache(caches: &MetadataCache) -> &LruCache<Self> {
+        &caches.albums
+    }
+}
+
+impl Cacheable for AlbumDetails {
+    fn cache(caches: &MetadataCache) -> &LruCache<Self> {
+        &caches.album_details
+    }
+}
@@ -0,0 +1,85 @@
+use librespot::metadata::{Album, Artist, Track};
+
+use super::adaptive::kbps;
+use super::metadata::MetadataCache;
+use super::player::SpotifyPlayer;
+
+use serde::Serialize;
+
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+/// Bump when a field of [`NowPlaying`] is removed, renamed or changes its meaning.
+/// Adding fields doesn't change the version.
+pub const NOW_PLAYING_SCHEMA_VERSION: u32 = 1;
+
+/// What is playing right now, in the format every status report uses.
+#[derive(Clone, Debug, Serialize)]
+pub struct NowPlaying {
+    pub schema_version: u32,
+    /// `spotify:track:...`
+    pub track_uri: String,
+    pub name: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub cover_url: Option<String>,
+    pub duration_ms: u32,
+    pub position_ms: u32,
+    pub is_playing: bool,
+    /// 0 to 65535
+    pub volume: u16,
+    /// Audio quality in kbit/s
+    pub bitrate: u32,
+}
+
+impl NowPlaying {
+    /// Look up the track the player is on. `None` if nothing played yet or the
+    /// metadata couldn't be fetched.
+    pub async fn current(
+        player: &Arc<Mutex<SpotifyPlayer>>,
+        metadata: &MetadataCache,
+    ) -> Option<NowPlaying> {
+        let (track_id, position, volume, bitrate) = {
+            let player = player.lock().await;
+            (
+                player.stats.current_track()?,
+                player.position(),
+                player.volume(),
+                player.player_config.bitrate,
+            )
+        };
+
+        let track: Track = metadata.get(track_id).await.ok()?;
+
+        let mut artists = Vec::with_capacity(track.artists.len());
+        for artist_id in track.artists.iter() {
+            if let Ok(artist) = metadata.get::<Artist>(*artist_id).await {
+                artists.push(artist.name);
+            }
+        }
+
+        let album = metadata
+            .get::<Album>(track.album)
+            .await
+            .map(|album| album.name)
+            .unwrap_or_default();
+
+        let (position_ms, is_playing) = position.unwrap_or_default();
+        let duration_ms = track.duration.max(0) as u32;
+
+        Some(NowPlaying {
+            schema_version: NOW_PLAYING_SCHEMA_VERSION,
+            track_uri: track_id.to_uri().unwrap_or_default(),
+            name: track.name.clone(),
+            artists,
+            album,
+            cover_url: metadata.cover_url(&track).await,
+            duration_ms,
+            position_ms: position_ms.min(duration_ms),
+            is_playing,
+            volume,
+            bitrate: kbps(bitrate),
+        })
+    }
+}
@@ -0,0 +1,144 @@
+use librespot::core::mercury::MercuryError;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use librespot::metadata::{Album, Artist, Playlist, Track};
+
+use super::metadata;
+
+use std::fmt;
+
+/// Length of the base62 ids in Spotify URIs and links.
+const BASE62_ID_LENGTH: usize = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlayKind {
+    Track,
+    Album,
+    Playlist,
+    Artist,
+}
+
+impl PlayKind {
+    fn from_name(name: &str) -> Option<PlayKind> {
+        match name {
+            "track" => Some(PlayKind::Track),
+            "album" => Some(PlayKind::Album),
+            "playlist" => Some(PlayKind::Playlist),
+            "artist" => Some(PlayKind::Artist),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PlayKind::Track => "track",
+            PlayKind::Album => "album",
+            PlayKind::Playlist => "playlist",
+            PlayKind::Artist => "artist",
+        }
+    }
+}
+
+/// Something `!play` can start, parsed from a `spotify:` URI or an open.spotify.com link.
+#[derive(Debug, Clone)]
+pub struct PlayTarget {
+    kind: PlayKind,
+    id: SpotifyId,
+    base62: String,
+}
+
+#[derive(Debug)]
+pub struct InvalidTarget;
+
+impl fmt::Display for InvalidTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected a Spotify URI like spotify:album:... or an open.spotify.com link \
+            to a track, album, playlist or artist"
+        )
+    }
+}
+
+impl std::error::Error for InvalidTarget {}
+
+impl PlayTarget {
+    /// Parse `spotify:album:ID`, `spotify:user:NAME:playlist:ID` or
+    /// `https://open.spotify.com/(intl-xx/)album/ID?si=...`.
+    pub fn parse(input: &str) -> Result<PlayTarget, InvalidTarget> {
+        // Discord wraps links in <> to suppress the embed
+        let input = input.trim().trim_start_matches('<').trim_end_matches('>');
+
+        let (kind, base62) = if let Some(uri) = input.strip_prefix("spotify:") {
+            match uri.split(':').collect::<Vec<_>>().as_slice() {
+                [kind, id] => (*kind, *id),
+                ["user", _, "playlist", id] => ("playlist", *id),
+                _ => return Err(InvalidTarget),
+            }
+        } else {
+            let path = ["https://", "http://", ""]
+                .iter()
+                .find_map(|scheme| input.strip_prefix(&format!("{}open.spotify.com/", scheme)))
+                .ok_or(InvalidTarget)?;
+            let path = path.split(['?', '#']).next().unwrap_or_default();
+
+            let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+            let mut first = segments.next().ok_or(InvalidTarget)?;
+            // Localized links look like open.spotify.com/intl-de/album/...
+            if first.starts_with("intl-") {
+                first = segments.next().ok_or(InvalidTarget)?;
+            }
+            let second = segments.next().ok_or(InvalidTarget)?;
+
+            match (first, second, segments.next(), segments.next()) {
+                (kind, id, None, None) => (kind, id),
+                ("user", _, Some("playlist"), Some(id)) => ("playlist", id),
+                _ => return Err(InvalidTarget),
+            }
+        };
+
+        let kind = PlayKind::from_name(kind).ok_or(InvalidTarget)?;
+        // Longer ids would overflow while decoding
+        if base62.len() != BASE62_ID_LENGTH {
+            return Err(InvalidTarget);
+        }
+        let id = SpotifyId::from_base62(base62).map_err(|_| InvalidTarget)?;
+
+        Ok(PlayTarget {
+            kind,
+            id,
+            base62: base62.to_string(),
+        })
+    }
+
+    /// URI of the context, as the Spotify clients report it.
+    pub fn uri(&self) -> String {
+        format!("spotify:{}:{}", self.kind.name(), self.base62)
+    }
+
+    /// Name of the target and the tracks to play, in order. For artists
+    /// these are their top tracks.
+    pub async fn resolve(
+        &self,
+        session: &Session,
+    ) -> Result<(String, Vec<SpotifyId>), MercuryError> {
+        Ok(match self.kind {
+            PlayKind::Track => {
+                let track: Track = metadata::get(session, self.id).await?;
+                (track.name, vec![track.id])
+            }
+            PlayKind::Album => {
+                let album: Album = metadata::get(session, self.id).await?;
+                (album.name, album.tracks)
+            }
+            PlayKind::Playlist => {
+                let playlist: Playlist = metadata::get(session, self.id).await?;
+                (playlist.name, playlist.tracks)
+            }
+            PlayKind::Artist => {
+                let artist: Artist = metadata::get(session, self.id).await?;
+                (artist.name, artist.top_tracks)
+            }
+        })
+    }
+}
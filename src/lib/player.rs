@@ -3,80 +3,692 @@ use librespot::core::{
     authentication::Credentials,
     cache::Cache,
     config::{ConnectConfig, DeviceType, SessionConfig},
-    session::Session,
+    session::{Session, SessionError},
 };
 use librespot::playback::{
     audio_backend,
     audio_backend::SinkResult,
     config::Bitrate,
-    config::{PlayerConfig, VolumeCtrl},
+    config::{NormalisationMethod, NormalisationType, PlayerConfig, VolumeCtrl},
     convert::Converter,
     decoder::AudioPacket,
     mixer::softmixer::SoftMixer,
-    mixer::{Mixer, MixerConfig},
+    mixer::{Mixer, MixerConfig, NoOpVolume, VolumeGetter},
     player::{Player, PlayerEventChannel},
 };
 
+use serenity::model::id::GuildId;
 use serenity::prelude::TypeMapKey;
 
+use super::devices::DeviceTracker;
+use super::events::{PlaybackEvent, PlaybackEvents};
+use super::play::PlayTarget;
+use super::stats::Stats;
+
 use std::clone::Clone;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::future::Future;
 use std::sync::{
-    mpsc::{sync_channel, Receiver, SyncSender},
-    Arc, Mutex,
+    atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    Arc, Mutex, Once,
 };
-use std::{io, mem};
+use std::time::Instant;
+use std::{fmt, io, mem};
 
 use byteorder::{ByteOrder, LittleEndian};
-use rubato::{FftFixedInOut, Resampler};
-use songbird::input::reader::MediaSource;
+use rubato::{FftFixedInOut, Resampler, ResamplerConstructionError};
+use serde::Deserialize;
+use songbird::input::{codec::Codec, reader::MediaSource};
+use songbird::tracks::TrackHandle;
+use tokio::time::{sleep, timeout, Duration};
+
+/// Number of interleaved channels in every frame passing through the sink.
+/// librespot decodes, the resampler processes and songbird expects this many channels.
+pub const CHANNELS: usize = 2;
+
+// The sink's frame layout (left/right buffers, stereo songbird input) is stereo only,
+// so catch a mismatch with librespot's output at compile time.
+const _: () = assert!(CHANNELS == 2);
+const _: () = assert!(CHANNELS == librespot::playback::NUM_CHANNELS as usize);
+
+/// Position of each channel within a frame. librespot interleaves its samples
+/// left first, songbird expects the same order, and rubato keeps its per-channel
+/// buffers in the order they are passed. Every step indexes with these, so the
+/// channels can't get swapped along the way.
+const LEFT: usize = 0;
+const RIGHT: usize = 1;
+
+/// Warn about passthrough packets only once, they would arrive for every packet.
+static PASSTHROUGH_WARNING: Once = Once::new();
+
+/// Warn about a replaced player still writing only once, it would for every packet.
+static STALE_WRITER_WARNING: Once = Once::new();
+
+/// Warn about a replaced songbird source still reading only once, it would for every read.
+static STALE_READER_WARNING: Once = Once::new();
+
+/// Chunks of silence run through the resampler by `EmittedSink::warm_up`.
+const WARMUP_CHUNKS: usize = 8;
+
+/// How often `EmittedSink::wait_for_prebuffer` checks the buffer.
+const PREBUFFER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long the gain takes to move between ducked and full volume, so ducking doesn't click.
+const DUCK_FADE: Duration = Duration::from_millis(150);
+
+/// How long a volume change takes from silent to full volume. Applying it at
+/// once makes an audible click.
+const VOLUME_FADE: Duration = Duration::from_millis(20);
+
+/// Most audio a single read returns. Larger reads from songbird get a partial read,
+/// so draining the channel doesn't keep the reader locked for long.
+const MAX_READ: Duration = Duration::from_millis(100);
+
+/// To convert f32 samples normalized as `-1.0..=1.0` to s16, multiply by 32768.
+/// This matches the scaling librespot's `Converter` uses.
+const S16_SCALE: f32 = 32768.0;
+
+/// PCM sample format emitted by the sink to songbird.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    #[default]
+    F32,
+    S16,
+}
+
+impl SampleFormat {
+    /// Codec songbird should use to decode the sink output.
+    pub fn codec(&self) -> Codec {
+        match self {
+            SampleFormat::F32 => Codec::FloatPcm,
+            SampleFormat::S16 => Codec::Pcm,
+        }
+    }
+
+    /// Size of one stereo frame in bytes.
+    fn frame_size(&self) -> usize {
+        match self {
+            SampleFormat::F32 => mem::size_of::<f32>() * CHANNELS,
+            SampleFormat::S16 => mem::size_of::<i16>() * CHANNELS,
+        }
+    }
+}
+
+/// How Connect volume maps to gain applied to the samples.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeControl {
+    #[default]
+    Linear,
+    Log,
+    /// Always play at full volume, volume changes are ignored
+    Fixed,
+}
+
+impl VolumeControl {
+    fn volume_ctrl(&self) -> VolumeCtrl {
+        match self {
+            VolumeControl::Linear => VolumeCtrl::Linear,
+            VolumeControl::Log => VolumeCtrl::Log(VolumeCtrl::DEFAULT_DB_RANGE),
+            VolumeControl::Fixed => VolumeCtrl::Fixed,
+        }
+    }
+}
+
+/// Buffering trade-off between latency and resilience to jitter.
+/// Durations are in output (48 kHz) frames.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LatencyProfile {
+    /// ~6 ms resampler chunks, 20 ms channel, no prebuffer, silence on underruns
+    Low,
+    /// ~23 ms resampler chunks, 100 ms channel, 50 ms prebuffer
+    #[default]
+    Balanced,
+    /// ~23 ms resampler chunks, 500 ms channel, 300 ms prebuffer
+    HighStability,
+}
+
+/// What the sink does when songbird wants audio and the channel is empty.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnderrunPolicy {
+    /// Wait for the prebuffer to refill, up to the stall timeout. Nothing is skipped,
+    /// but songbird waits too, which it may notice as a hiccup
+    Block,
+    /// Answer right away with silence until the prebuffer refilled, so songbird keeps
+    /// its timing and the underrun is a short dropout
+    Silence,
+}
+
+/// What the sink does when songbird falls behind and the channel is full.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverrunPolicy {
+    /// Wait for songbird, never dropping audio but letting delay build up
+    #[default]
+    Block,
+    /// Drop the oldest frames, keeping the delay bounded by the channel capacity
+    OverwriteOldest,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BufferSettings {
+    /// Input frames resampled at once
+    pub chunk_size: usize,
+    /// Output frames the channel between write and read can hold
+    pub channel_capacity: usize,
+    /// Output frames to collect before audio is handed to songbird, after startup or an underrun
+    pub prebuffer: usize,
+    /// How long to wait for librespot before reporting a stall and sending silence
+    pub stall_timeout: Duration,
+    pub overrun: OverrunPolicy,
+    pub underrun: UnderrunPolicy,
+}
+
+impl LatencyProfile {
+    pub fn buffer_settings(&self) -> BufferSettings {
+        match self {
+            LatencyProfile::Low => BufferSettings {
+                chunk_size: 256,
+                channel_capacity: 960,
+                prebuffer: 0,
+                stall_timeout: Duration::from_secs(2),
+                overrun: OverrunPolicy::Block,
+                // There is no prebuffer to wait for, any underrun would stall songbird
+                underrun: UnderrunPolicy::Silence,
+            },
+            LatencyProfile::Balanced => BufferSettings {
+                chunk_size: 1024,
+                channel_capacity: 4800,
+                prebuffer: 2400,
+                stall_timeout: Duration::from_secs(5),
+                overrun: OverrunPolicy::Block,
+                underrun: UnderrunPolicy::Block,
+            },
+            LatencyProfile::HighStability => BufferSettings {
+                chunk_size: 1024,
+                channel_capacity: 24000,
+                prebuffer: 14400,
+                stall_timeout: Duration::from_secs(10),
+                overrun: OverrunPolicy::Block,
+                underrun: UnderrunPolicy::Block,
+            },
+        }
+    }
+}
 
 pub struct SpotifyPlayer {
-    player_config: PlayerConfig,
+    pub player_config: PlayerConfig,
     pub emitted_sink: EmittedSink,
     pub session: Session,
     pub spirc: Option<Box<Spirc>>,
     pub event_channel: Option<Arc<tokio::sync::Mutex<PlayerEventChannel>>>,
+    /// The only volume control: Connect volume changes and !mute scale the samples
+    /// in the sink, songbird plays them at unity gain
     mixer: Box<SoftMixer>,
+    volume_control: VolumeControl,
     pub bot_autoplay: bool,
     pub device_name: String,
+    pub devices: DeviceTracker,
+    pub stats: Arc<Stats>,
+    /// The songbird track currently streaming the sink, if any
+    pub track_handle: Option<TrackHandle>,
+    /// Volume to restore on unmute, set while muted
+    muted_volume: Option<u16>,
+    /// Guild the current volume belongs to, see `use_guild_volume`
+    volume_guild: Option<GuildId>,
+    /// Published to `subscribe`rs, fed by the event loop
+    pub events: Arc<PlaybackEvents>,
+    /// Played once Spotify Connect is first enabled, see `INITIAL_CONTEXT`
+    initial_context: Option<PlayTarget>,
+    /// Requested fade between tracks, zero when disabled.
+    /// librespot 0.4 only decodes one track at a time, so this isn't applied yet.
+    pub crossfade: Duration,
+    /// Set while the player is rebuilt, so stopping the old one isn't treated as the end of playback
+    pub restarting: bool,
+    /// Set while voice playback is being recovered, so a second failure handler doesn't rejoin too
+    pub recovering: bool,
+    /// Last position librespot reported, see `position`
+    position: Option<ReportedPosition>,
+    /// How the session was created, to log in again once it's closed
+    login: Option<LoginSettings>,
+}
+
+#[derive(Clone)]
+struct LoginSettings {
+    credentials: Credentials,
+    cache_dir: Option<String>,
+    max_attempts: Option<u32>,
+    timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ReportedPosition {
+    position_ms: u32,
+    duration_ms: u32,
+    reported_at: Instant,
+    playing: bool,
+}
+
+struct SinkReader {
+    receiver: Receiver<[f32; CHANNELS]>,
+    // Frames taken off the channel while prebuffering
+    pending: VecDeque<[f32; CHANNELS]>,
+    primed: bool,
+    // Set while librespot isn't producing audio, so a stall is only logged once
+    stalled: bool,
+    // When the sink started sending silence after an underrun, with `UnderrunPolicy::Silence`
+    silent_since: Option<Instant>,
+}
+
+impl SinkReader {
+    /// Block until at least `frames` (and at least one) frames are pending.
+    /// Gives up once no frame arrived for `timeout`, returns whether any frames are pending.
+    fn prime(&mut self, frames: usize, timeout: Duration) -> bool {
+        while self.pending.len() < frames.max(1) {
+            match self.receiver.recv_timeout(timeout) {
+                Ok(frame) => self.pending.push_back(frame),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => panic!("EmittedSink sender dropped"),
+            }
+        }
+
+        if self.pending.is_empty() {
+            return false;
+        }
+
+        self.primed = true;
+        self.stalled = false;
+        true
+    }
+
+    /// Like `prime`, but only takes the frames that already arrived instead of waiting.
+    fn try_prime(&mut self, frames: usize) -> bool {
+        while self.pending.len() < frames.max(1) {
+            match self.receiver.try_recv() {
+                Ok(frame) => self.pending.push_back(frame),
+                Err(_) => return false,
+            }
+        }
+
+        self.primed = true;
+        self.stalled = false;
+        self.silent_since = None;
+        true
+    }
+}
+
+/// Additional consumer of the frames sent to songbird, e.g. a recording.
+pub trait FrameTap: Send {
+    fn write_frame(&mut self, frame: &[f32; CHANNELS]);
+}
+
+/// Snapshot of the sink for `!audiostats`. Frame counts are in output frames.
+#[derive(Clone, Copy, Debug)]
+pub struct SinkDiagnostics {
+    pub input_rate: u32,
+    pub output_rate: u32,
+    /// Input frames resampled at once, `None` when the rates match and nothing is resampled
+    pub resampler_chunk_size: Option<usize>,
+    pub sample_format: SampleFormat,
+    pub buffered: usize,
+    pub capacity: usize,
+    pub prebuffer: usize,
+    pub overrun: OverrunPolicy,
+    pub underrun: UnderrunPolicy,
+}
+
+/// Gain the sink applies to the samples, moving towards its target a step per frame
+/// instead of jumping to it. Used for the Spotify volume and for ducking.
+pub struct FadingGain {
+    // f32 bits
+    target: AtomicU32,
+    current: AtomicU32,
+}
+
+impl FadingGain {
+    fn new() -> FadingGain {
+        FadingGain {
+            target: AtomicU32::new(1.0f32.to_bits()),
+            current: AtomicU32::new(1.0f32.to_bits()),
+        }
+    }
+
+    /// Fade to `gain`, from 0.0 (silent) to 1.0 (unchanged).
+    pub fn fade_to(&self, gain: f32) {
+        self.target
+            .store(gain.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Change to `gain` without fading, for the initial volume.
+    fn set(&self, gain: f32) {
+        let gain = gain.clamp(0.0, 1.0).to_bits();
+        self.target.store(gain, Ordering::Relaxed);
+        self.current.store(gain, Ordering::Relaxed);
+    }
+
+    /// Gain for the next frame, `step` closer to the target than the last one.
+    fn next(&self, step: f32) -> f32 {
+        let target = f32::from_bits(self.target.load(Ordering::Relaxed));
+        let current = f32::from_bits(self.current.load(Ordering::Relaxed));
+
+        if current == target {
+            return current;
+        }
+
+        let next = if current < target {
+            (current + step).min(target)
+        } else {
+            (current - step).max(target)
+        };
+        self.current.store(next.to_bits(), Ordering::Relaxed);
+        next
+    }
 }
 
 pub struct EmittedSink {
-    sender: Arc<SyncSender<[f32; 2]>>,
-    pub receiver: Arc<Mutex<Receiver<[f32; 2]>>>,
-    input_buffer: Arc<Mutex<(Vec<f32>, Vec<f32>)>>,
-    resampler: Arc<Mutex<FftFixedInOut<f32>>>,
+    sender: Arc<SyncSender<[f32; CHANNELS]>>,
+    taps: Arc<Mutex<Vec<Box<dyn FrameTap>>>>,
+    reader: Arc<Mutex<SinkReader>>,
+    // Deinterleaved input waiting for a full resampler chunk, indexed by `LEFT` and `RIGHT`
+    input_buffer: Arc<Mutex<[Vec<f32>; CHANNELS]>>,
+    // None when the input and output rates match and samples are passed through untouched
+    resampler: Option<Arc<Mutex<FftFixedInOut<f32>>>>,
     resampler_input_frames_needed: usize,
+    pub sample_format: SampleFormat,
+    /// Sample rate of the frames handed to songbird and the taps
+    pub output_rate: u32,
+    input_rate: u32,
+    chunk_size: usize,
+    channel_capacity: usize,
+    // Frames sent but not yet read, in the channel or pending in the reader
+    buffered: Arc<AtomicUsize>,
+    prebuffer: usize,
+    stall_timeout: Duration,
+    overrun: OverrunPolicy,
+    underrun: UnderrunPolicy,
+    // Frames dropped since the channel last had room, to log an overrun once it's over
+    overrun_dropped: Arc<AtomicU64>,
+    // Lowered while someone in the voice channel speaks
+    duck: Arc<FadingGain>,
+    // Gain change per output frame while fading
+    duck_step: f32,
+    // The Spotify volume, librespot itself plays at full volume so it can be faded here
+    volume: Arc<FadingGain>,
+    volume_step: f32,
+    volume_source: Arc<Mutex<Option<Box<dyn VolumeGetter + Send>>>>,
+    // Only the writer whose id is `active_writer` may write, see `writer`
+    writer_id: u64,
+    active_writer: Arc<AtomicU64>,
+    // Only the reader whose id is `active_reader` gets samples, see `reader`
+    reader_id: u64,
+    active_reader: Arc<AtomicU64>,
+    stats: Arc<Stats>,
 }
 
 impl EmittedSink {
-    fn new() -> EmittedSink {
+    fn new(
+        input_rate: usize,
+        output_rate: usize,
+        sample_format: SampleFormat,
+        buffer_settings: BufferSettings,
+        stats: Arc<Stats>,
+    ) -> Result<EmittedSink, ResamplerConstructionError> {
         // By setting the sync_channel bound to at least the output frame size of one resampling
         // step (1120 for a chunk size of 1024 and our frequency settings) the number of
         // synchronizations needed between EmittedSink::write and EmittedSink::read can be reduced.
-        let (sender, receiver) = sync_channel::<[f32; 2]>(1120);
+        // All latency profiles satisfy this for their chunk size.
+        let (sender, receiver) = sync_channel::<[f32; CHANNELS]>(buffer_settings.channel_capacity);
 
-        let resampler = FftFixedInOut::<f32>::new(
-            librespot::playback::SAMPLE_RATE as usize,
-            songbird::constants::SAMPLE_RATE_RAW,
-            1024,
-            2,
-        )
-        .unwrap();
+        let resampler = if input_rate == output_rate {
+            None
+        } else {
+            Some(FftFixedInOut::<f32>::new(
+                input_rate,
+                output_rate,
+                buffer_settings.chunk_size,
+                CHANNELS,
+            )?)
+        };
 
-        let resampler_input_frames_needed = resampler.input_frames_max();
+        let resampler_input_frames_needed = resampler
+            .as_ref()
+            .map(|resampler| resampler.input_frames_max())
+            .unwrap_or_default();
 
-        EmittedSink {
+        Ok(EmittedSink {
             sender: Arc::new(sender),
-            receiver: Arc::new(Mutex::new(receiver)),
-            input_buffer: Arc::new(Mutex::new((
+            taps: Arc::new(Mutex::new(Vec::new())),
+            reader: Arc::new(Mutex::new(SinkReader {
+                receiver,
+                pending: VecDeque::with_capacity(buffer_settings.prebuffer),
+                primed: false,
+                stalled: false,
+                silent_since: None,
+            })),
+            input_buffer: Arc::new(Mutex::new([
                 Vec::with_capacity(resampler_input_frames_needed),
                 Vec::with_capacity(resampler_input_frames_needed),
-            ))),
-            resampler: Arc::new(Mutex::new(resampler)),
+            ])),
+            resampler: resampler.map(|resampler| Arc::new(Mutex::new(resampler))),
             resampler_input_frames_needed,
+            sample_format,
+            output_rate: output_rate as u32,
+            input_rate: input_rate as u32,
+            chunk_size: buffer_settings.chunk_size,
+            channel_capacity: buffer_settings.channel_capacity,
+            buffered: Arc::new(AtomicUsize::new(0)),
+            prebuffer: buffer_settings.prebuffer,
+            stall_timeout: buffer_settings.stall_timeout,
+            overrun: buffer_settings.overrun,
+            underrun: buffer_settings.underrun,
+            overrun_dropped: Arc::new(AtomicU64::new(0)),
+            duck: Arc::new(FadingGain::new()),
+            duck_step: 1.0 / (DUCK_FADE.as_secs_f32() * output_rate as f32),
+            volume: Arc::new(FadingGain::new()),
+            volume_step: 1.0 / (VOLUME_FADE.as_secs_f32() * output_rate as f32),
+            volume_source: Arc::new(Mutex::new(None)),
+            writer_id: 0,
+            active_writer: Arc::new(AtomicU64::new(0)),
+            reader_id: 0,
+            active_reader: Arc::new(AtomicU64::new(0)),
+            stats,
+        })
+    }
+
+    fn send_frame(&self, left: f32, right: f32, converter: &mut Converter) {
+        let gain = self.volume.next(self.volume_step) * self.duck.next(self.duck_step);
+        let (left, right) = (left * gain, right * gain);
+
+        let mut frame = [0.0; CHANNELS];
+        (frame[LEFT], frame[RIGHT]) = match self.sample_format {
+            SampleFormat::F32 => (left, right),
+            // Quantize (with dither) here, where the converter is available,
+            // so that read only has to do an exact scale to i16.
+            SampleFormat::S16 => (
+                converter.scale(left as f64, S16_SCALE as f64) as f32 / S16_SCALE,
+                converter.scale(right as f64, S16_SCALE as f64) as f32 / S16_SCALE,
+            ),
+        };
+
+        for tap in self.taps.lock().unwrap().iter_mut() {
+            tap.write_frame(&frame);
+        }
+
+        // Count the frame before sending it, so the reader never counts it out first
+        self.buffered.fetch_add(1, Ordering::Relaxed);
+
+        match self.overrun {
+            OverrunPolicy::Block => self.sender.send(frame).unwrap(),
+            OverrunPolicy::OverwriteOldest => self.overwrite_oldest(frame),
+        }
+    }
+
+    /// Send `frame`, making room by dropping the oldest frame if the channel is full.
+    fn overwrite_oldest(&self, frame: [f32; CHANNELS]) {
+        let frame = match self.sender.try_send(frame) {
+            Ok(()) => {
+                let dropped = self.overrun_dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    tracing::warn!(
+                        "Songbird fell behind, dropped {} frames ({} ms) to stay live",
+                        dropped,
+                        dropped * 1000 / self.output_rate as u64
+                    );
+                }
+                return;
+            }
+            Err(TrySendError::Full(frame)) => frame,
+            Err(TrySendError::Disconnected(_)) => panic!("EmittedSink receiver dropped"),
+        };
+
+        // While the reader holds the lock it is taking frames off the channel,
+        // so waiting for room won't take long
+        if let Ok(reader) = self.reader.try_lock() {
+            if reader.receiver.try_recv().is_ok() {
+                self.buffered.fetch_sub(1, Ordering::Relaxed);
+                self.overrun_dropped.fetch_add(1, Ordering::Relaxed);
+                self.stats.dropped_frame();
+            }
+        }
+
+        self.sender.send(frame).unwrap()
+    }
+
+    /// A clone to hand to a new librespot player. From now on only it may write to
+    /// the sink, writes of the previous players are dropped, so a player that is
+    /// still shutting down can't interleave its samples with the new one's.
+    pub fn writer(&self) -> EmittedSink {
+        let mut writer = self.clone();
+        writer.writer_id = self.active_writer.fetch_add(1, Ordering::SeqCst) + 1;
+
+        // Don't resample the start of the new stream together with the end of the old one
+        for channel in self.input_buffer.lock().unwrap().iter_mut() {
+            channel.clear();
+        }
+
+        writer
+    }
+
+    /// A clone to hand to a new songbird source. From now on only it reads the sink,
+    /// the previous sources read the end of their stream, so two sources playing at
+    /// once (e.g. after a duplicate join) can't split the samples between them.
+    pub fn reader(&self) -> EmittedSink {
+        let mut reader = self.clone();
+        reader.reader_id = self.active_reader.fetch_add(1, Ordering::SeqCst) + 1;
+        reader
+    }
+
+    /// Keep reading the sink on a thread of its own, for when nothing else consumes it
+    /// and the taps should still receive audio.
+    pub fn drain(&self) {
+        let mut reader = self.reader();
+        std::thread::spawn(move || {
+            let mut buff = [0u8; 4096];
+            while let Ok(1..) = io::Read::read(&mut reader, &mut buff) {}
+        });
+    }
+
+    /// Run the resampler over a few chunks of silence and throw the result away, so
+    /// the first track doesn't pay for touching its buffers for the first time.
+    /// Silence leaves the resampler in the same state as a fresh one.
+    pub fn warm_up(&self) {
+        let Some(resampler) = self.resampler.as_ref() else {
+            return;
+        };
+
+        let mut resampler = resampler.lock().unwrap();
+        let input = vec![vec![0.0; self.resampler_input_frames_needed]; CHANNELS];
+        let mut output = resampler.output_buffer_allocate();
+
+        for _ in 0..WARMUP_CHUNKS {
+            if let Err(error) = resampler.process_into_buffer(&input, &mut output, None) {
+                tracing::warn!("Could not warm up the resampler: {}", error);
+                return;
+            }
         }
     }
+
+    /// Wait until librespot filled the prebuffer (or sent anything, without one), so
+    /// songbird's first reads don't block its mixer while the first track is loading.
+    /// Gives up after `stall_timeout`, returns whether the prebuffer was filled.
+    pub async fn wait_for_prebuffer(&self) -> bool {
+        let wanted = self.prebuffer.max(1);
+
+        timeout(self.stall_timeout, async {
+            while self.buffered.load(Ordering::Relaxed) < wanted {
+                sleep(PREBUFFER_POLL_INTERVAL).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// A sink like this one but with `buffer_settings`, keeping the taps, the volume and ducking.
+    /// This sink is retired: writes to it are dropped and its readers get the end of the stream.
+    fn rebuilt(
+        &self,
+        buffer_settings: BufferSettings,
+    ) -> Result<EmittedSink, ResamplerConstructionError> {
+        let mut sink = EmittedSink::new(
+            self.input_rate as usize,
+            self.output_rate as usize,
+            self.sample_format,
+            buffer_settings,
+            self.stats.clone(),
+        )?;
+
+        sink.taps = self.taps.clone();
+        sink.duck = self.duck.clone();
+        sink.volume = self.volume.clone();
+        sink.volume_source = self.volume_source.clone();
+
+        self.active_writer.fetch_add(1, Ordering::SeqCst);
+        self.active_reader.fetch_add(1, Ordering::SeqCst);
+
+        // A writer may be blocked on the full channel, nothing reads it anymore
+        if let Ok(reader) = self.reader.lock() {
+            while reader.receiver.try_recv().is_ok() {}
+        }
+
+        Ok(sink)
+    }
+
+    /// Current state of the pipeline between librespot and songbird.
+    pub fn diagnostics(&self) -> SinkDiagnostics {
+        SinkDiagnostics {
+            input_rate: self.input_rate,
+            output_rate: self.output_rate,
+            resampler_chunk_size: self.resampler.as_ref().map(|_| self.chunk_size),
+            sample_format: self.sample_format,
+            buffered: self.buffered.load(Ordering::Relaxed),
+            capacity: self.channel_capacity,
+            prebuffer: self.prebuffer,
+            overrun: self.overrun,
+            underrun: self.underrun,
+        }
+    }
+
+    /// The gain lowered while someone speaks, see `FadingGain`.
+    pub fn duck_gain(&self) -> Arc<FadingGain> {
+        self.duck.clone()
+    }
+
+    /// Apply the volume of `source` (the mixer) to the samples, fading on changes.
+    /// Players writing to the sink must not apply it themselves, see `NoOpVolume`.
+    fn set_volume_source(&self, source: Box<dyn VolumeGetter + Send>) {
+        self.volume.set(source.attenuation_factor() as f32);
+        *self.volume_source.lock().unwrap() = Some(source);
+    }
+
+    /// Also hand every frame sent to songbird to `tap`.
+    pub fn add_tap(&self, tap: Box<dyn FrameTap>) {
+        self.taps.lock().unwrap().push(tap);
+    }
 }
 
 impl audio_backend::Sink for EmittedSink {
@@ -88,38 +700,81 @@ impl audio_backend::Sink for EmittedSink {
         Ok(())
     }
 
-    fn write(&mut self, packet: AudioPacket, _converter: &mut Converter) -> SinkResult<()> {
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        if self.active_writer.load(Ordering::SeqCst) != self.writer_id {
+            STALE_WRITER_WARNING.call_once(|| {
+                tracing::warn!("A replaced Spotify player is still playing, muting it")
+            });
+            return Ok(());
+        }
+
+        let samples = match &packet {
+            AudioPacket::Samples(samples) => samples,
+            // Only produced with passthrough, which isn't enabled. Decoding it as
+            // samples would play noise, so drop it
+            AudioPacket::OggData(_) => {
+                PASSTHROUGH_WARNING
+                    .call_once(|| tracing::warn!("librespot sent undecoded Ogg data, dropping it"));
+                return Ok(());
+            }
+        };
+
+        // librespot sends empty packets, there is nothing to resample
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        // Picked up once per packet, the fade smooths the steps in between
+        if let Some(source) = self.volume_source.lock().unwrap().as_ref() {
+            self.volume.fade_to(source.attenuation_factor() as f32);
+        }
+
+        let Some(resampler) = self.resampler.as_ref() else {
+            for c in samples.chunks_exact(CHANNELS) {
+                self.send_frame(c[LEFT] as f32, c[RIGHT] as f32, converter);
+            }
+
+            return Ok(());
+        };
+
         let frames_needed = self.resampler_input_frames_needed;
         let mut input_buffer = self.input_buffer.lock().unwrap();
 
-        let mut resampler = self.resampler.lock().unwrap();
+        let mut resampler = resampler.lock().unwrap();
 
         let mut resampled_buffer = resampler.output_buffer_allocate();
 
-        for c in packet.samples().unwrap().chunks_exact(2) {
-            input_buffer.0.push(c[0] as f32);
-            input_buffer.1.push(c[1] as f32);
-            if input_buffer.0.len() == frames_needed {
-                resampler
-                    .process_into_buffer(
-                        &[
-                            &input_buffer.0[0..frames_needed],
-                            &input_buffer.1[0..frames_needed],
-                        ],
-                        &mut resampled_buffer,
-                        None,
-                    )
-                    .unwrap();
-
-                input_buffer.0.clear();
-                input_buffer.1.clear();
-
-                let sender = self.sender.clone();
-
-                for i in 0..resampled_buffer[0].len() {
-                    sender
-                        .send([resampled_buffer[0][i], resampled_buffer[1][i]])
-                        .unwrap()
+        for c in samples.chunks_exact(CHANNELS) {
+            input_buffer[LEFT].push(c[LEFT] as f32);
+            input_buffer[RIGHT].push(c[RIGHT] as f32);
+            if input_buffer[LEFT].len() == frames_needed {
+                let result = resampler.process_into_buffer(
+                    &[
+                        &input_buffer[LEFT][0..frames_needed],
+                        &input_buffer[RIGHT][0..frames_needed],
+                    ],
+                    &mut resampled_buffer,
+                    None,
+                );
+
+                input_buffer[LEFT].clear();
+                input_buffer[RIGHT].clear();
+
+                if let Err(error) = result {
+                    // Emit silence of the expected length so the stream keeps its timing
+                    tracing::warn!("Resampling failed, emitting silence: {}", error);
+
+                    for _ in 0..resampler.output_frames_next() {
+                        self.send_frame(0.0, 0.0, converter);
+                    }
+                    continue;
+                }
+
+                for (&left, &right) in resampled_buffer[LEFT]
+                    .iter()
+                    .zip(resampled_buffer[RIGHT].iter())
+                {
+                    self.send_frame(left, right, converter);
                 }
             }
         }
@@ -128,42 +783,184 @@ impl audio_backend::Sink for EmittedSink {
     }
 }
 
+impl EmittedSink {
+    fn encode_frame(&self, frame: &[f32; CHANNELS], buff: &mut [u8]) {
+        match self.sample_format {
+            SampleFormat::F32 => LittleEndian::write_f32_into(frame, buff),
+            SampleFormat::S16 => LittleEndian::write_i16_into(
+                &[
+                    (frame[LEFT] * S16_SCALE) as i16,
+                    (frame[RIGHT] * S16_SCALE) as i16,
+                ],
+                buff,
+            ),
+        }
+    }
+}
+
+impl EmittedSink {
+    /// librespot didn't produce audio in time. Fill `buff` with silence instead of
+    /// blocking songbird forever, and report the stall.
+    fn stalled(&self, reader: &mut SinkReader, buff: &mut [u8]) -> usize {
+        if !reader.stalled {
+            tracing::warn!(
+                "No audio from librespot for {:?}, sending silence",
+                self.stall_timeout
+            );
+            self.stats.stall();
+            reader.stalled = true;
+        }
+        reader.primed = false;
+
+        let sample_size = self.sample_format.frame_size();
+        let len = buff.len() - buff.len() % sample_size;
+        buff[..len].fill(0);
+        len
+    }
+
+    /// Wait for the prebuffer, recording how long that took. Returns false if
+    /// librespot sent nothing within the stall timeout.
+    fn prime(&self, reader: &mut SinkReader) -> bool {
+        let started = Instant::now();
+        if !reader.prime(self.prebuffer, self.stall_timeout) {
+            return false;
+        }
+
+        self.stats.primed(started.elapsed());
+        true
+    }
+
+    /// Read with `UnderrunPolicy::Silence`: fill `buff` with whatever already arrived
+    /// and the rest with silence, never waiting for librespot.
+    fn read_or_silence(&self, reader: &mut SinkReader, buff: &mut [u8]) -> usize {
+        let sample_size = self.sample_format.frame_size();
+        let len = buff.len() - buff.len() % sample_size;
+
+        let was_primed = reader.primed;
+        let silent_since = reader.silent_since;
+        let mut bytes_written = 0;
+        if reader.primed || reader.try_prime(self.prebuffer) {
+            if !was_primed {
+                self.stats
+                    .primed(silent_since.map_or(Duration::ZERO, |since| since.elapsed()));
+            }
+
+            while bytes_written < len {
+                let Some(frame) = reader
+                    .pending
+                    .pop_front()
+                    .or_else(|| reader.receiver.try_recv().ok())
+                else {
+                    break;
+                };
+
+                self.encode_frame(
+                    &frame,
+                    &mut buff[bytes_written..(bytes_written + sample_size)],
+                );
+                bytes_written += sample_size;
+            }
+
+            self.buffered
+                .fetch_sub(bytes_written / sample_size, Ordering::Relaxed);
+        }
+
+        if bytes_written == len {
+            return len;
+        }
+
+        // Ran dry: send silence until the prebuffer refilled
+        buff[bytes_written..len].fill(0);
+        reader.primed = false;
+
+        match reader.silent_since {
+            None => {
+                // Not an underrun if nothing played yet
+                if was_primed {
+                    tracing::debug!("Buffer ran dry, sending silence until it refills");
+                    self.stats.underrun();
+                }
+                reader.silent_since = Some(Instant::now());
+            }
+            Some(since) if !reader.stalled && since.elapsed() >= self.stall_timeout => {
+                tracing::warn!(
+                    "No audio from librespot for {:?}, sending silence",
+                    self.stall_timeout
+                );
+                self.stats.stall();
+                reader.stalled = true;
+            }
+            Some(_) => {}
+        }
+
+        len
+    }
+}
+
 impl io::Read for EmittedSink {
     fn read(&mut self, buff: &mut [u8]) -> io::Result<usize> {
-        let sample_size = mem::size_of::<f32>() * 2;
+        let sample_size = self.sample_format.frame_size();
 
         if buff.len() < sample_size {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                "EmittedSink does not support read buffer too small to guarantee \
-                holding one audio sample (8 bytes)",
+                format!(
+                    "EmittedSink does not support read buffer too small to guarantee \
+                    holding one audio sample ({} bytes)",
+                    sample_size
+                ),
             ));
         }
 
-        let receiver = self.receiver.lock().unwrap();
+        if self.active_reader.load(Ordering::SeqCst) != self.reader_id {
+            STALE_READER_WARNING.call_once(|| {
+                tracing::warn!("A replaced voice source is still reading, ending it")
+            });
+            return Ok(0);
+        }
+
+        let max_len = (MAX_READ.as_secs_f32() * self.output_rate as f32) as usize * sample_size;
+        let len = buff.len().min(max_len.max(sample_size));
+        let buff = &mut buff[..len];
+
+        let mut reader = self.reader.lock().unwrap();
+
+        if self.underrun == UnderrunPolicy::Silence {
+            return Ok(self.read_or_silence(&mut reader, buff));
+        }
+
+        if !reader.primed && !self.prime(&mut reader) {
+            return Ok(self.stalled(&mut reader, buff));
+        }
 
         let mut bytes_written = 0;
         while bytes_written + (sample_size - 1) < buff.len() {
-            if bytes_written == 0 {
+            let frame = if let Some(frame) = reader.pending.pop_front() {
+                frame
+            } else if let Ok(frame) = reader.receiver.try_recv() {
+                frame
+            } else if bytes_written == 0 {
                 // We can not return 0 bytes because songbird then thinks that the track has ended,
                 // therefore block until at least one stereo data set can be returned.
-
-                let sample = receiver.recv().unwrap();
-                LittleEndian::write_f32_into(
-                    &sample,
-                    &mut buff[bytes_written..(bytes_written + sample_size)],
-                );
-            } else if let Ok(data) = receiver.try_recv() {
-                LittleEndian::write_f32_into(
-                    &data,
-                    &mut buff[bytes_written..(bytes_written + sample_size)],
-                );
+                self.stats.underrun();
+                if !self.prime(&mut reader) {
+                    return Ok(self.stalled(&mut reader, buff));
+                }
+                reader.pending.pop_front().unwrap()
             } else {
                 break;
-            }
+            };
+
+            self.encode_frame(
+                &frame,
+                &mut buff[bytes_written..(bytes_written + sample_size)],
+            );
             bytes_written += sample_size;
         }
 
+        self.buffered
+            .fetch_sub(bytes_written / sample_size, Ordering::Relaxed);
+
         Ok(bytes_written)
     }
 }
@@ -187,99 +984,468 @@ impl MediaSource for EmittedSink {
 impl Clone for EmittedSink {
     fn clone(&self) -> EmittedSink {
         EmittedSink {
-            receiver: self.receiver.clone(),
+            reader: self.reader.clone(),
             sender: self.sender.clone(),
+            taps: self.taps.clone(),
             input_buffer: self.input_buffer.clone(),
             resampler: self.resampler.clone(),
             resampler_input_frames_needed: self.resampler_input_frames_needed,
+            sample_format: self.sample_format,
+            output_rate: self.output_rate,
+            input_rate: self.input_rate,
+            chunk_size: self.chunk_size,
+            channel_capacity: self.channel_capacity,
+            buffered: self.buffered.clone(),
+            prebuffer: self.prebuffer,
+            stall_timeout: self.stall_timeout,
+            overrun: self.overrun,
+            underrun: self.underrun,
+            overrun_dropped: self.overrun_dropped.clone(),
+            duck: self.duck.clone(),
+            duck_step: self.duck_step,
+            volume: self.volume.clone(),
+            volume_step: self.volume_step,
+            volume_source: self.volume_source.clone(),
+            writer_id: self.writer_id,
+            active_writer: self.active_writer.clone(),
+            reader_id: self.reader_id,
+            active_reader: self.active_reader.clone(),
+            stats: self.stats.clone(),
         }
     }
 }
 
+/// Give the restarted Spirc time to announce itself before handing it the old state.
+const REBUILD_LOAD_DELAY: Duration = Duration::from_secs(1);
+
+/// Give the new Spirc time to announce itself before loading `INITIAL_CONTEXT`.
+const INITIAL_CONTEXT_DELAY: Duration = Duration::from_secs(1);
+
+/// Rebuild the Spotify player on the existing session after applying `configure`,
+/// then continue the current track where it was.
+/// Returns whether playback was resumed, or `None` if the player isn't running.
+pub async fn rebuild_player(
+    player: &tokio::sync::Mutex<SpotifyPlayer>,
+    configure: impl FnOnce(&mut SpotifyPlayer),
+) -> Option<bool> {
+    let (tracker, resume) = {
+        let mut player = player.lock().await;
+
+        player.spirc.as_ref()?;
+
+        let tracker = player.devices.clone();
+        let state = tracker.bot_state();
+
+        configure(&mut player);
+
+        tracing::info!("Restarting the Spotify player");
+        player.restarting = true;
+        player.restart_connect().await;
+
+        (
+            tracker,
+            state.map(|state| (player.session.device_id().to_string(), state)),
+        )
+    };
+
+    let resumed = match resume {
+        Some((ident, state)) => {
+            sleep(REBUILD_LOAD_DELAY).await;
+            tracker.load(&ident, state);
+            true
+        }
+        None => false,
+    };
+
+    player.lock().await.restarting = false;
+
+    Some(resumed)
+}
+
+/// Delay before the first login retry, doubled after every failed attempt.
+const LOGIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Give up on a login attempt after this long, unless configured otherwise.
+pub const DEFAULT_LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest delay between two login attempts.
+const MAX_LOGIN_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Log in to Spotify, retrying network failures and attempts taking longer than
+/// `login_timeout` with exponential backoff up to `max_attempts` times, or forever if `None`.
+/// Rejected credentials are returned right away since retrying won't help.
+pub async fn connect_session(
+    credentials: Credentials,
+    cache_dir: Option<String>,
+    max_attempts: Option<u32>,
+    login_timeout: Duration,
+) -> Result<Session, SessionError> {
+    let session_config = SessionConfig::default();
+
+    // 4 GB
+    let mut cache_limit: u64 = 10;
+    cache_limit = cache_limit.pow(9);
+    cache_limit *= 4;
+
+    let cache = Cache::new(
+        cache_dir.clone(),
+        cache_dir.clone(),
+        cache_dir,
+        Some(cache_limit),
+    )
+    .ok();
+
+    let mut delay = LOGIN_RETRY_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        match max_attempts {
+            Some(max_attempts) => tracing::info!(
+                "Logging in to Spotify (attempt {}/{})",
+                attempt,
+                max_attempts
+            ),
+            None => tracing::info!("Logging in to Spotify (attempt {})", attempt),
+        }
+
+        let connect = Session::connect(
+            session_config.clone(),
+            credentials.clone(),
+            cache.clone(),
+            false,
+        );
+
+        let error = match timeout(login_timeout, connect).await {
+            Ok(Ok((session, _))) => return Ok(session),
+            Ok(Err(error)) => error,
+            // Report it as a network error so it's retried like one
+            Err(_) => SessionError::IoError(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no answer from Spotify within {:?}", login_timeout),
+            )),
+        };
+
+        // librespot doesn't export its authentication error, but only network
+        // failures carry an io::Error, a rejected login has no source
+        let retryable = match &error {
+            SessionError::IoError(_) => true,
+            SessionError::AuthenticationError(error) => error
+                .source()
+                .is_some_and(|source| source.is::<io::Error>()),
+        };
+
+        if !retryable || max_attempts.is_some_and(|max_attempts| attempt >= max_attempts) {
+            return Err(error);
+        }
+
+        tracing::warn!("Spotify login failed: {}, retrying in {:?}", error, delay);
+
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_LOGIN_RETRY_DELAY);
+        attempt += 1;
+    }
+}
+
 pub struct SpotifyPlayerKey;
 
 impl TypeMapKey for SpotifyPlayerKey {
     type Value = Arc<tokio::sync::Mutex<SpotifyPlayer>>;
 }
 
-impl SpotifyPlayer {
-    pub async fn new(
-        username: String,
-        password: String,
-        quality: Bitrate,
-        cache_dir: Option<String>,
-        bot_autoplay: bool,
-        device_name: String,
-    ) -> SpotifyPlayer {
-        let credentials = Credentials::with_password(username, password);
+/// Why a `SpotifyPlayer` could not be built.
+#[derive(Debug)]
+pub enum PlayerError {
+    /// No credentials to log in with were given
+    MissingCredentials,
+    Session(SessionError),
+    /// The resampler can't convert librespot's output to the requested rate
+    Resampler(ResamplerConstructionError),
+}
+
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlayerError::MissingCredentials => write!(f, "no credentials given"),
+            PlayerError::Session(error) => write!(f, "could not log in to Spotify: {}", error),
+            PlayerError::Resampler(error) => write!(f, "could not create resampler: {}", error),
+        }
+    }
+}
 
-        let session_config = SessionConfig::default();
+impl Error for PlayerError {}
 
-        // 4 GB
-        let mut cache_limit: u64 = 10;
-        cache_limit = cache_limit.pow(9);
-        cache_limit *= 4;
+impl From<SessionError> for PlayerError {
+    fn from(error: SessionError) -> Self {
+        PlayerError::Session(error)
+    }
+}
+
+/// Configures and creates a `SpotifyPlayer`, logging in to Spotify.
+pub struct SpotifyPlayerBuilder {
+    credentials: Option<Credentials>,
+    cache_dir: Option<String>,
+    max_login_attempts: Option<u32>,
+    login_timeout: Duration,
+    bitrate: Bitrate,
+    normalisation: bool,
+    track_gain_only: bool,
+    autoplay: bool,
+    device_name: String,
+    sample_format: SampleFormat,
+    output_sample_rate: u32,
+    buffer_settings: BufferSettings,
+    volume_control: VolumeControl,
+    initial_context: Option<PlayTarget>,
+}
+
+impl SpotifyPlayerBuilder {
+    pub fn new() -> SpotifyPlayerBuilder {
+        SpotifyPlayerBuilder {
+            credentials: None,
+            cache_dir: None,
+            max_login_attempts: None,
+            login_timeout: DEFAULT_LOGIN_TIMEOUT,
+            bitrate: Bitrate::Bitrate320,
+            normalisation: false,
+            track_gain_only: false,
+            autoplay: false,
+            device_name: "Aoede".to_string(),
+            sample_format: SampleFormat::default(),
+            output_sample_rate: songbird::constants::SAMPLE_RATE_RAW as u32,
+            buffer_settings: LatencyProfile::default().buffer_settings(),
+            volume_control: VolumeControl::default(),
+            initial_context: None,
+        }
+    }
 
-        let cache = Cache::new(
-            cache_dir.clone(),
-            cache_dir.clone(),
-            cache_dir,
-            Some(cache_limit),
+    /// Log in with `credentials` when building.
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Directory for librespot's credentials and audio cache.
+    pub fn cache(mut self, cache_dir: Option<String>) -> Self {
+        self.cache_dir = cache_dir;
+        self
+    }
+
+    /// Give up logging in after this many failed attempts, `None` retries forever.
+    pub fn max_login_attempts(mut self, max_attempts: Option<u32>) -> Self {
+        self.max_login_attempts = max_attempts;
+        self
+    }
+
+    /// Give up on a login attempt that took longer than this.
+    pub fn login_timeout(mut self, login_timeout: Duration) -> Self {
+        self.login_timeout = login_timeout;
+        self
+    }
+
+    pub fn bitrate(mut self, bitrate: Bitrate) -> Self {
+        self.bitrate = bitrate;
+        self
+    }
+
+    /// Let librespot normalise the loudness of tracks.
+    pub fn normalisation(mut self, normalisation: bool) -> Self {
+        self.normalisation = normalisation;
+        self
+    }
+
+    /// Normalise with only each track's gain, capped by its peak, instead of
+    /// librespot's dynamic limiter. Turns on normalisation.
+    pub fn track_gain_only(mut self, track_gain_only: bool) -> Self {
+        self.track_gain_only = track_gain_only;
+        self
+    }
+
+    /// Play similar tracks once the context ends.
+    pub fn autoplay(mut self, autoplay: bool) -> Self {
+        self.autoplay = autoplay;
+        self
+    }
+
+    /// Name of the Spotify Connect device.
+    pub fn device_name(mut self, device_name: String) -> Self {
+        self.device_name = device_name;
+        self
+    }
+
+    pub fn sample_format(mut self, sample_format: SampleFormat) -> Self {
+        self.sample_format = sample_format;
+        self
+    }
+
+    pub fn output_sample_rate(mut self, output_sample_rate: u32) -> Self {
+        self.output_sample_rate = output_sample_rate;
+        self
+    }
+
+    pub fn buffer_settings(mut self, buffer_settings: BufferSettings) -> Self {
+        self.buffer_settings = buffer_settings;
+        self
+    }
+
+    pub fn volume_control(mut self, volume_control: VolumeControl) -> Self {
+        self.volume_control = volume_control;
+        self
+    }
+
+    /// Start playing `initial_context` the first time Spotify Connect is enabled.
+    pub fn initial_context(mut self, initial_context: Option<PlayTarget>) -> Self {
+        self.initial_context = initial_context;
+        self
+    }
+
+    pub async fn build(mut self) -> Result<SpotifyPlayer, PlayerError> {
+        let login = LoginSettings {
+            credentials: self
+                .credentials
+                .take()
+                .ok_or(PlayerError::MissingCredentials)?,
+            cache_dir: self.cache_dir.take(),
+            max_attempts: self.max_login_attempts,
+            timeout: self.login_timeout,
+        };
+
+        let session = connect_session(
+            login.credentials.clone(),
+            login.cache_dir.clone(),
+            login.max_attempts,
+            login.timeout,
         )
-        .ok();
+        .await?;
 
-        let (session, _) = Session::connect(session_config, credentials, cache, false)
-            .await
-            .expect("Error creating session");
+        let mut player = self.build_with_session(session)?;
+        player.login = Some(login);
+
+        Ok(player)
+    }
 
-        let player_config = PlayerConfig {
-            bitrate: quality,
+    fn build_with_session(self, session: Session) -> Result<SpotifyPlayer, PlayerError> {
+        let mut player_config = PlayerConfig {
+            bitrate: self.bitrate,
+            normalisation: self.normalisation,
             ..Default::default()
         };
 
-        let emitted_sink = EmittedSink::new();
+        if self.track_gain_only {
+            // The basic method scales the whole track, so there's no pumping
+            player_config.normalisation = true;
+            player_config.normalisation_type = NormalisationType::Track;
+            player_config.normalisation_method = NormalisationMethod::Basic;
+        }
+
+        let stats = Arc::new(Stats::new());
 
-        let cloned_sink = emitted_sink.clone();
+        let emitted_sink = EmittedSink::new(
+            librespot::playback::SAMPLE_RATE as usize,
+            self.output_sample_rate as usize,
+            self.sample_format,
+            self.buffer_settings,
+            stats.clone(),
+        )
+        .map_err(PlayerError::Resampler)?;
+
+        let cloned_sink = emitted_sink.writer();
 
         let mixer = Box::new(SoftMixer::open(MixerConfig {
-            volume_ctrl: VolumeCtrl::Linear,
+            volume_ctrl: self.volume_control.volume_ctrl(),
             ..MixerConfig::default()
         }));
 
+        emitted_sink.set_volume_source(mixer.get_soft_volume());
+
         let (_player, rx) = Player::new(
             player_config.clone(),
             session.clone(),
-            mixer.get_soft_volume(),
+            Box::new(NoOpVolume),
             move || Box::new(cloned_sink),
         );
 
-        SpotifyPlayer {
+        let devices = DeviceTracker::new(session.clone());
+
+        Ok(SpotifyPlayer {
             player_config,
             emitted_sink,
             session,
             spirc: None,
             event_channel: Some(Arc::new(tokio::sync::Mutex::new(rx))),
             mixer,
-            bot_autoplay,
-            device_name,
-        }
+            volume_control: self.volume_control,
+            bot_autoplay: self.autoplay,
+            device_name: self.device_name,
+            devices,
+            stats,
+            track_handle: None,
+            muted_volume: None,
+            volume_guild: None,
+            events: Arc::new(PlaybackEvents::new()),
+            initial_context: self.initial_context,
+            crossfade: Duration::ZERO,
+            restarting: false,
+            recovering: false,
+            position: None,
+            login: None,
+        })
+    }
+}
+
+impl Default for SpotifyPlayerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpotifyPlayer {
+    pub fn builder() -> SpotifyPlayerBuilder {
+        SpotifyPlayerBuilder::new()
+    }
+
+    /// Log in with a username and password, the constructor from before the builder.
+    /// Kept for existing callers, new code should use `builder`.
+    #[allow(dead_code)]
+    pub async fn new(
+        username: String,
+        password: String,
+        quality: Bitrate,
+        cache_dir: Option<String>,
+        bot_autoplay: bool,
+        device_name: String,
+    ) -> Result<SpotifyPlayer, PlayerError> {
+        SpotifyPlayer::builder()
+            .credentials(Credentials::with_password(username, password))
+            .cache(cache_dir)
+            .bitrate(quality)
+            .autoplay(bot_autoplay)
+            .device_name(device_name)
+            .build()
+            .await
     }
 
+    /// Show up in Spotify Connect. Does nothing if already connected, so joining
+    /// voice again quickly doesn't start a second player on the same sink.
     pub async fn enable_connect(&mut self) {
+        if self.spirc.is_some() {
+            tracing::debug!("Spotify Connect is already enabled");
+            return;
+        }
+
         let config = ConnectConfig {
             name: self.device_name.clone(),
             device_type: DeviceType::AudioDongle,
             initial_volume: None,
-            has_volume_ctrl: true,
+            has_volume_ctrl: self.volume_control(),
             autoplay: self.bot_autoplay,
         };
 
-        let cloned_sink = self.emitted_sink.clone();
+        let cloned_sink = self.emitted_sink.writer();
 
         let (player, player_events) = Player::new(
             self.player_config.clone(),
             self.session.clone(),
-            self.mixer.get_soft_volume(),
+            Box::new(NoOpVolume),
             move || Box::new(cloned_sink),
         );
 
@@ -296,13 +1462,466 @@ impl SpotifyPlayer {
 
         let mut channel_lock = self.event_channel.as_ref().unwrap().lock().await;
         *channel_lock = player_events;
+        drop(channel_lock);
+
+        if let Some(target) = self.initial_context.take() {
+            self.start_initial_context(target);
+        }
+    }
+
+    /// Play `target` on the freshly enabled device, unless another device of the
+    /// account is already playing.
+    fn start_initial_context(&self, target: PlayTarget) {
+        let tracker = self.devices.clone();
+        let session = self.session.clone();
+
+        tokio::spawn(async move {
+            sleep(INITIAL_CONTEXT_DELAY).await;
+
+            if tracker.other_device_active() {
+                tracing::info!(
+                    "Another device is playing, not starting initial context {}",
+                    target.uri()
+                );
+                return;
+            }
+
+            match target.resolve(&session).await {
+                Ok((name, tracks)) if !tracks.is_empty() => {
+                    tracing::info!("Starting initial context {}", name);
+                    tracker.play(target.uri(), &tracks);
+                }
+                Ok((name, _)) => tracing::warn!("Initial context {} has no tracks", name),
+                Err(_) => tracing::warn!("Could not find initial context {}", target.uri()),
+            }
+        });
+    }
+
+    /// Remember the position librespot reported with a playing or paused event.
+    pub fn set_position(&mut self, position_ms: u32, duration_ms: u32, playing: bool) {
+        self.position = Some(ReportedPosition {
+            position_ms,
+            duration_ms,
+            reported_at: Instant::now(),
+            playing,
+        });
+    }
+
+    /// Forget the position once playback stopped.
+    pub fn clear_position(&mut self) {
+        self.position = None;
+    }
+
+    /// Current position in the track and whether it's playing, if a track was reported.
+    /// librespot only reports the position when it changes unexpectedly (start, seek,
+    /// unpause), so while playing this adds the time since, up to the end of the track.
+    pub fn position(&self) -> Option<(u32, bool)> {
+        self.position.map(|reported| {
+            if reported.playing {
+                let elapsed = reported.reported_at.elapsed().as_millis() as u32;
+                let position_ms = reported.position_ms.saturating_add(elapsed);
+
+                (position_ms.min(reported.duration_ms), true)
+            } else {
+                (reported.position_ms, false)
+            }
+        })
+    }
+
+    /// Switch the mixer to `volume_control`, keeping the volume. Spotify Connect
+    /// still uses the old mixer until it's restarted, see `rebuild_player`.
+    pub fn set_volume_control(&mut self, volume_control: VolumeControl) {
+        let mixer = Box::new(SoftMixer::open(MixerConfig {
+            volume_ctrl: volume_control.volume_ctrl(),
+            ..MixerConfig::default()
+        }));
+        mixer.set_volume(self.mixer.volume());
+
+        self.emitted_sink.set_volume_source(mixer.get_soft_volume());
+        self.mixer = mixer;
+        self.volume_control = volume_control;
+    }
+
+    /// Switch to a sink with `buffer_settings`. The songbird track reading the old sink
+    /// is stopped, `join_and_play` streams the new one. librespot writes to it once
+    /// Spotify Connect is restarted, see `rebuild_player`.
+    pub fn set_buffer_settings(
+        &mut self,
+        buffer_settings: BufferSettings,
+    ) -> Result<(), PlayerError> {
+        // Forget the track first, so its end isn't treated as a failure
+        if let Some(track_handle) = self.track_handle.take() {
+            let _ = track_handle.stop();
+        }
+
+        self.emitted_sink = self
+            .emitted_sink
+            .rebuilt(buffer_settings)
+            .map_err(PlayerError::Resampler)?;
+
+        Ok(())
+    }
+
+    /// Whether the mixer applies volume changes, false with fixed volume.
+    pub fn volume_control(&self) -> bool {
+        self.volume_control != VolumeControl::Fixed
+    }
+
+    /// Current volume, from 0 to 65535. While muted, the volume to restore.
+    pub fn volume(&self) -> u16 {
+        self.muted_volume.unwrap_or_else(|| self.mixer.volume())
+    }
+
+    /// Change the volume by `step` percent, clamped to 0-100. Returns the new volume in percent.
+    /// While muted, this changes the volume restored on unmute.
+    pub fn change_volume(&mut self, step: i32) -> u32 {
+        let max = u16::MAX as i32;
+        let percent = ((self.volume() as i32 * 100 + max / 2) / max + step).clamp(0, 100);
+        let volume = (percent * max / 100) as u16;
+
+        match self.muted_volume.as_mut() {
+            Some(muted_volume) => *muted_volume = volume,
+            None => self.set_mixer_volume(volume),
+        }
+
+        percent as u32
+    }
+
+    /// Switch to the volume last used in `guild_id` when streaming there, after
+    /// remembering the current one for the guild it was used in. Staying in the
+    /// same guild keeps the volume.
+    pub fn use_guild_volume(&mut self, guild_id: GuildId) {
+        if !self.volume_control() || self.volume_guild == Some(guild_id) {
+            return;
+        }
+
+        self.remember_guild_volume();
+        self.volume_guild = Some(guild_id);
+
+        let Some(volume) = self.stats.guild_volume(guild_id) else {
+            return;
+        };
+
+        tracing::debug!(guild_id = %guild_id, "Restoring the volume of this guild");
+
+        match self.muted_volume.as_mut() {
+            Some(muted_volume) => *muted_volume = volume,
+            None => self.set_mixer_volume(volume),
+        }
+    }
+
+    /// Remember the current volume for the guild it is used in, so it's restored
+    /// the next time the bot streams there.
+    pub fn remember_guild_volume(&self) {
+        if let Some(guild_id) = self.volume_guild {
+            self.stats.set_guild_volume(guild_id, self.volume());
+        }
+    }
+
+    /// Set the volume librespot doesn't know about, so subscribers still hear of it.
+    fn set_mixer_volume(&self, volume: u16) {
+        self.mixer.set_volume(volume);
+        self.events.volume_changed(volume);
+    }
+
+    /// Follow playback, see `PlaybackEvent` for what is sent and how.
+    // For embedders, the bot itself handles librespot's events
+    #[allow(dead_code)]
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<PlaybackEvent> {
+        self.events.subscribe()
+    }
+
+    /// Silence playback, remembering the current volume. Returns false if already muted.
+    pub fn mute(&mut self) -> bool {
+        if self.muted_volume.is_some() {
+            return false;
+        }
+
+        self.muted_volume = Some(self.mixer.volume());
+        self.set_mixer_volume(0);
+
+        true
+    }
+
+    /// Restore the volume from before muting. Returns false if not muted.
+    pub fn unmute(&mut self) -> bool {
+        match self.muted_volume.take() {
+            Some(volume) => {
+                self.set_mixer_volume(volume);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Log in again the way the current session was created. `None` if the player
+    /// was given a session instead of logging in itself.
+    /// This doesn't borrow the player, so it isn't blocked while logging in.
+    pub fn relogin(&self) -> Option<impl Future<Output = Result<Session, SessionError>>> {
+        let login = self.login.clone()?;
+
+        Some(connect_session(
+            login.credentials,
+            login.cache_dir,
+            login.max_attempts,
+            login.timeout,
+        ))
+    }
+
+    /// Continue on `session` after the previous one was closed, reconnecting
+    /// Spotify Connect if it was enabled.
+    pub async fn replace_session(&mut self, session: Session) {
+        let connected = self.spirc.is_some();
+
+        self.restarting = true;
+        self.disable_connect().await;
+
+        self.session = session;
+        self.devices = DeviceTracker::new(self.session.clone());
+
+        if connected {
+            self.enable_connect().await;
+        }
+        self.restarting = false;
+    }
+
+    /// Rebuild the player and Spirc on the existing session, for when librespot got stuck.
+    pub async fn restart_connect(&mut self) {
+        self.disable_connect().await;
+        self.enable_connect().await;
     }
 
     pub async fn disable_connect(&mut self) {
-        if let Some(spirc) = self.spirc.as_ref() {
+        if let Some(spirc) = self.spirc.take() {
             spirc.shutdown();
 
             self.event_channel.as_ref().unwrap().lock().await.close();
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    const RATE: usize = 48000;
+
+    pub(crate) fn sink(input_rate: usize, sample_format: SampleFormat) -> EmittedSink {
+        let buffer_settings = BufferSettings {
+            chunk_size: 1024,
+            channel_capacity: RATE,
+            prebuffer: 0,
+            stall_timeout: Duration::from_millis(10),
+            overrun: OverrunPolicy::Block,
+            underrun: UnderrunPolicy::Block,
+        };
+
+        EmittedSink::new(
+            input_rate,
+            RATE,
+            sample_format,
+            buffer_settings,
+            Arc::new(Stats::new()),
+        )
+        .unwrap()
+    }
+
+    fn write(sink: &mut EmittedSink, samples: &[f64]) {
+        audio_backend::Sink::write(
+            sink,
+            AudioPacket::Samples(samples.to_vec()),
+            &mut Converter::new(None),
+        )
+        .unwrap();
+    }
+
+    fn read_f32(sink: &mut EmittedSink, samples: usize) -> Vec<f32> {
+        let mut buff = vec![0; samples * mem::size_of::<f32>()];
+        let len = sink.read(&mut buff).unwrap();
+
+        let mut read = vec![0.0; len / mem::size_of::<f32>()];
+        LittleEndian::read_f32_into(&buff[..len], &mut read);
+        read
+    }
+
+    fn read_s16(sink: &mut EmittedSink, samples: usize) -> Vec<i16> {
+        let mut buff = vec![0; samples * mem::size_of::<i16>()];
+        let len = sink.read(&mut buff).unwrap();
+
+        let mut read = vec![0; len / mem::size_of::<i16>()];
+        LittleEndian::read_i16_into(&buff[..len], &mut read);
+        read
+    }
+
+    #[test]
+    fn f32_round_trip() {
+        let samples = [
+            0.0, -0.0, 0.5, -0.5, 1.0, -1.0, 0.123_456, -0.999_9, 1e-6, -1e-6,
+        ];
+
+        let mut writer = sink(RATE, SampleFormat::F32).writer();
+        let mut reader = writer.reader();
+        write(&mut writer, &samples);
+
+        let expected: Vec<f32> = samples.iter().map(|sample| *sample as f32).collect();
+        assert_eq!(read_f32(&mut reader, samples.len()), expected);
+    }
+
+    #[test]
+    fn s16_round_trip() {
+        let samples = [
+            0.0,
+            0.5,
+            -0.5,
+            -1.0,
+            0.25,
+            -0.25,
+            1.0 / 32768.0,
+            -3.0 / 32768.0,
+        ];
+
+        let mut writer = sink(RATE, SampleFormat::S16).writer();
+        let mut reader = writer.reader();
+        write(&mut writer, &samples);
+
+        assert_eq!(
+            read_s16(&mut reader, samples.len()),
+            [0, 16384, -16384, -32768, 8192, -8192, 1, -3]
+        );
+    }
+
+    #[test]
+    fn round_trip_over_several_packets() {
+        let packets: Vec<Vec<f64>> = (0..4)
+            .map(|packet| {
+                (0..64)
+                    .map(|sample| (packet * 64 + sample) as f64 / 512.0 - 0.25)
+                    .collect()
+            })
+            .collect();
+
+        let mut writer = sink(RATE, SampleFormat::F32).writer();
+        let mut reader = writer.reader();
+        for packet in &packets {
+            write(&mut writer, packet);
+        }
+
+        let expected: Vec<f32> = packets
+            .concat()
+            .iter()
+            .map(|sample| *sample as f32)
+            .collect();
+        assert_eq!(read_f32(&mut reader, expected.len()), expected);
+    }
+
+    #[test]
+    fn matching_rates_skip_the_resampler() {
+        let sink = sink(RATE, SampleFormat::F32);
+        assert_eq!(sink.diagnostics().resampler_chunk_size, None);
+
+        // Every input frame comes out right away, without a resampler's delay
+        let mut writer = sink.writer();
+        write(&mut writer, &[0.25; 2 * 100]);
+        assert_eq!(sink.diagnostics().buffered, 100);
+    }
+
+    #[test]
+    fn differing_rates_resample() {
+        let sink = sink(44100, SampleFormat::F32);
+        assert_eq!(sink.diagnostics().resampler_chunk_size, Some(1024));
+    }
+
+    #[test]
+    fn left_comes_before_right() {
+        let mut writer = sink(RATE, SampleFormat::F32).writer();
+        let mut reader = writer.reader();
+        write(&mut writer, &[0.1, 0.9, 0.2, 0.8]);
+
+        assert_eq!(read_f32(&mut reader, 4), [0.1, 0.9, 0.2, 0.8]);
+    }
+
+    #[test]
+    fn resampling_keeps_the_channels_apart() {
+        let mut writer = sink(44100, SampleFormat::F32).writer();
+        let mut reader = writer.reader();
+
+        // A constant left channel and a silent right one, long enough for a few chunks
+        let samples: Vec<f64> = (0..44100 / 10).flat_map(|_| [0.5, 0.0]).collect();
+        write(&mut writer, &samples);
+
+        let frames = read_f32(&mut reader, 2 * 4000);
+        assert!(frames.len() >= 2 * 2048);
+
+        // Skip the resampler's ramp up at the start
+        for frame in frames[2 * 1024..].chunks_exact(CHANNELS) {
+            assert!((frame[LEFT] - 0.5).abs() < 0.01, "left is {}", frame[LEFT]);
+            assert!(frame[RIGHT].abs() < 0.01, "right is {}", frame[RIGHT]);
+        }
+    }
+
+    #[test]
+    fn replaced_writers_are_dropped() {
+        let sink = sink(RATE, SampleFormat::F32);
+        let mut old_writer = sink.writer();
+        let mut new_writer = sink.writer();
+
+        write(&mut old_writer, &[0.1; 2 * 10]);
+        assert_eq!(sink.diagnostics().buffered, 0);
+
+        write(&mut new_writer, &[0.2; 2 * 10]);
+        assert_eq!(sink.diagnostics().buffered, 10);
+        assert_eq!(read_f32(&mut sink.reader(), 2 * 10), [0.2; 2 * 10]);
+    }
+
+    #[test]
+    fn replaced_readers_get_nothing() {
+        let sink = sink(RATE, SampleFormat::F32);
+        let mut old_reader = sink.reader();
+        let mut new_reader = sink.reader();
+
+        write(&mut sink.writer(), &[0.3; 2 * 10]);
+
+        let mut buff = [0; 2 * 10 * 4];
+        assert_eq!(old_reader.read(&mut buff).unwrap(), 0);
+        assert_eq!(read_f32(&mut new_reader, 2 * 10), [0.3; 2 * 10]);
+    }
+
+    #[test]
+    fn rebuilt_sinks_retire_the_old_one() {
+        let old = sink(RATE, SampleFormat::F32);
+        let mut old_writer = old.writer();
+        let mut old_reader = old.reader();
+        write(&mut old_writer, &[0.1; 2 * 10]);
+
+        let rebuilt = old.rebuilt(LatencyProfile::Low.buffer_settings()).unwrap();
+        write(&mut old_writer, &[0.2; 2 * 10]);
+        write(&mut rebuilt.writer(), &[0.3; 2 * 10]);
+
+        let mut buff = [0; 2 * 10 * 4];
+        assert_eq!(old_reader.read(&mut buff).unwrap(), 0);
+        assert_eq!(read_f32(&mut rebuilt.reader(), 2 * 10), [0.3; 2 * 10]);
+        assert_eq!(rebuilt.diagnostics().capacity, 960);
+    }
+
+    #[test]
+    fn oversized_reads_are_capped() {
+        let mut writer = sink(RATE, SampleFormat::F32).writer();
+        let mut reader = writer.reader();
+
+        let max_frames = (MAX_READ.as_secs_f32() * RATE as f32) as usize;
+        write(&mut writer, &vec![0.4; 2 * 2 * max_frames]);
+
+        // Returns at most MAX_READ of what's buffered, the rest stays for the next read
+        let mut buff = vec![0; 4 * SampleFormat::F32.frame_size() * max_frames];
+        assert_eq!(
+            reader.read(&mut buff).unwrap(),
+            max_frames * SampleFormat::F32.frame_size()
+        );
+        assert_eq!(
+            reader.read(&mut buff).unwrap(),
+            max_frames * SampleFormat::F32.frame_size()
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use super::player::{FrameTap, CHANNELS};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::mem;
+
+/// Size of the RIFF header written before the samples.
+const HEADER_SIZE: u32 = 44;
+
+/// WAVE_FORMAT_IEEE_FLOAT
+const FORMAT_FLOAT: u16 = 3;
+
+/// Update the sizes in the header this often (in frames), so the file stays
+/// readable if the bot is killed while recording.
+const HEADER_UPDATE_INTERVAL: u32 = 48000;
+
+/// Records the frames handed to songbird to a 32-bit float WAV file.
+pub struct WavRecorder {
+    writer: BufWriter<File>,
+    frames: u32,
+    // Set after a write error, to stop recording instead of logging every frame
+    failed: bool,
+}
+
+impl WavRecorder {
+    pub fn create(path: &str, sample_rate: u32) -> io::Result<WavRecorder> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        let block_align = (mem::size_of::<f32>() * CHANNELS) as u16;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_u32::<LittleEndian>(HEADER_SIZE - 8)?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_u32::<LittleEndian>(16)?;
+        writer.write_u16::<LittleEndian>(FORMAT_FLOAT)?;
+        writer.write_u16::<LittleEndian>(CHANNELS as u16)?;
+        writer.write_u32::<LittleEndian>(sample_rate)?;
+        writer.write_u32::<LittleEndian>(sample_rate * block_align as u32)?;
+        writer.write_u16::<LittleEndian>(block_align)?;
+        writer.write_u16::<LittleEndian>(32)?;
+        writer.write_all(b"data")?;
+        writer.write_u32::<LittleEndian>(0)?;
+
+        Ok(WavRecorder {
+            writer,
+            frames: 0,
+            failed: false,
+        })
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        let data_size = self.frames * (mem::size_of::<f32>() * CHANNELS) as u32;
+
+        self.writer.flush()?;
+
+        let file = self.writer.get_mut();
+        file.seek(SeekFrom::Start(4))?;
+        file.write_u32::<LittleEndian>(HEADER_SIZE - 8 + data_size)?;
+        file.seek(SeekFrom::Start(40))?;
+        file.write_u32::<LittleEndian>(data_size)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(())
+    }
+
+    fn write(&mut self, frame: &[f32; CHANNELS]) -> io::Result<()> {
+        for sample in frame {
+            self.writer.write_f32::<LittleEndian>(*sample)?;
+        }
+
+        self.frames += 1;
+
+        if self.frames.is_multiple_of(HEADER_UPDATE_INTERVAL) {
+            self.update_header()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FrameTap for WavRecorder {
+    fn write_frame(&mut self, frame: &[f32; CHANNELS]) {
+        if self.failed {
+            return;
+        }
+
+        if let Err(error) = self.write(frame) {
+            tracing::warn!("Could not write recording, stopping it: {}", error);
+            self.failed = true;
+        }
+    }
+}
+
+impl Drop for WavRecorder {
+    fn drop(&mut self) {
+        if !self.failed {
+            let _ = self.update_header();
+        }
+    }
+}
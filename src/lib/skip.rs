@@ -0,0 +1,112 @@
+use librespot::core::spotify_id::SpotifyId;
+
+use super::player::SpotifyPlayer;
+
+use std::sync::{Arc, Mutex};
+
+use tokio::time::{sleep, Duration};
+
+/// Skip a track that hasn't started this long after librespot began loading it.
+const LOAD_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Stop skipping after this many tracks in a row could not be loaded, in case
+/// nothing in the playlist is playable.
+const MAX_CONSECUTIVE_SKIPS: u32 = 10;
+
+#[derive(Default)]
+struct SkipState {
+    // Track librespot is loading, cleared once it starts
+    loading: Option<SpotifyId>,
+    // Bumped on every load so a timeout only applies to the load that started it
+    generation: u64,
+    skipped: u32,
+}
+
+/// Skips tracks that can't be loaded, e.g. because they aren't available in the
+/// account's region, so playback doesn't stall in the middle of a playlist.
+#[derive(Clone, Default)]
+pub struct Skipper {
+    state: Arc<Mutex<SkipState>>,
+}
+
+impl Skipper {
+    /// librespot started loading `track_id`. Skips it if it hasn't started after `LOAD_TIMEOUT`.
+    pub fn loading(&self, player: Arc<tokio::sync::Mutex<SpotifyPlayer>>, track_id: SpotifyId) {
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            state.loading = Some(track_id);
+            state.generation += 1;
+            state.generation
+        };
+
+        let skipper = self.clone();
+        tokio::spawn(async move {
+            sleep(LOAD_TIMEOUT).await;
+
+            if skipper.state.lock().unwrap().generation != generation {
+                return;
+            }
+
+            if skipper.failed(track_id, "timed out loading") {
+                if let Some(spirc) = player.lock().await.spirc.as_ref() {
+                    spirc.next();
+                }
+            }
+        });
+    }
+
+    /// The loading track started (or is ready, paused), or playback stopped.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.loading = None;
+        state.skipped = 0;
+    }
+
+    /// librespot ended `track_id`. If it never started, loading it failed and
+    /// librespot already moves on to the next track.
+    pub async fn end_of_track(
+        &self,
+        player: &tokio::sync::Mutex<SpotifyPlayer>,
+        track_id: SpotifyId,
+    ) {
+        let loading = self.state.lock().unwrap().loading == Some(track_id);
+
+        if loading && !self.failed(track_id, "could not be loaded") {
+            // Pausing keeps librespot from loading one unplayable track after another
+            if let Some(spirc) = player.lock().await.spirc.as_ref() {
+                spirc.pause();
+            }
+        }
+    }
+
+    /// Record that `track_id` failed to load, returns whether to skip it.
+    fn failed(&self, track_id: SpotifyId, reason: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if state.loading != Some(track_id) {
+            return false;
+        }
+
+        state.loading = None;
+        state.skipped += 1;
+
+        let uri = track_id.to_uri().unwrap_or_default();
+
+        if state.skipped > MAX_CONSECUTIVE_SKIPS {
+            tracing::error!(
+                track_uri = %uri,
+                "Track {}, giving up after {} unplayable tracks in a row",
+                reason,
+                MAX_CONSECUTIVE_SKIPS
+            );
+            return false;
+        }
+
+        tracing::warn!(
+            track_uri = %uri,
+            "Track {}, it may not be available in this region. Skipping it",
+            reason
+        );
+        true
+    }
+}
@@ -0,0 +1,282 @@
+use librespot::core::spotify_id::SpotifyId;
+
+use serde::{Deserialize, Serialize};
+
+use serenity::model::id::{ChannelId, GuildId};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What the bot is doing in voice, updated on join, leave, pause and resume.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum VoiceStatus {
+    #[default]
+    NotInVoice,
+    /// In a voice channel, but playback is paused
+    Idle,
+    /// In a voice channel and sending audio
+    Streaming,
+}
+
+impl fmt::Display for VoiceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoiceStatus::NotInVoice => write!(f, "not in voice"),
+            VoiceStatus::Idle => write!(f, "idle"),
+            VoiceStatus::Streaming => write!(f, "streaming"),
+        }
+    }
+}
+
+/// Counters kept across restarts in the `STATS_PATH` file.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(default)]
+pub struct LifetimeStats {
+    pub tracks_played: u64,
+    pub listening_secs: u64,
+    pub reconnects: u64,
+}
+
+/// Contents of the `STATS_PATH` file.
+#[derive(Serialize, Deserialize, Default)]
+struct StatsFile {
+    #[serde(flatten)]
+    lifetime: LifetimeStats,
+    /// Volume last used in each guild, by guild id
+    #[serde(default)]
+    guild_volumes: BTreeMap<u64, u16>,
+}
+
+/// Counters shared between the event loop, the sink and the commands.
+pub struct Stats {
+    started_at: Instant,
+    tracks_played: AtomicU64,
+    underruns: AtomicU64,
+    stalls: AtomicU64,
+    dropped_frames: AtomicU64,
+    reconnects: AtomicU64,
+    // Time spent streaming before the current stream, and when that started
+    listening: Mutex<(Duration, Option<Instant>)>,
+    // Totals of previous runs and the file they are saved to
+    previous: Mutex<LifetimeStats>,
+    path: Mutex<Option<PathBuf>>,
+    last_track: Mutex<Option<SpotifyId>>,
+    voice_channel: Mutex<Option<(GuildId, ChannelId)>>,
+    voice_status: Mutex<VoiceStatus>,
+    guild_volumes: Mutex<BTreeMap<u64, u16>>,
+    last_prime: Mutex<Option<Duration>>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started_at: Instant::now(),
+            tracks_played: AtomicU64::new(0),
+            underruns: AtomicU64::new(0),
+            stalls: AtomicU64::new(0),
+            dropped_frames: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            listening: Mutex::new((Duration::ZERO, None)),
+            previous: Mutex::new(LifetimeStats::default()),
+            path: Mutex::new(None),
+            last_track: Mutex::new(None),
+            voice_channel: Mutex::new(None),
+            voice_status: Mutex::new(VoiceStatus::NotInVoice),
+            guild_volumes: Mutex::new(BTreeMap::new()),
+            last_prime: Mutex::new(None),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Count a track as played, ignoring repeated events for the same track.
+    /// Returns whether `track_id` just started.
+    pub fn track_playing(&self, track_id: SpotifyId) -> bool {
+        let mut last_track = self.last_track.lock().unwrap();
+
+        if *last_track == Some(track_id) {
+            return false;
+        }
+
+        *last_track = Some(track_id);
+        self.tracks_played.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// The track that played last, which is still current unless playback stopped.
+    pub fn current_track(&self) -> Option<SpotifyId> {
+        *self.last_track.lock().unwrap()
+    }
+
+    pub fn tracks_played(&self) -> u64 {
+        self.tracks_played.load(Ordering::Relaxed)
+    }
+
+    pub fn underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    /// The sink collected its prebuffer, which took `took`.
+    pub fn primed(&self, took: Duration) {
+        *self.last_prime.lock().unwrap() = Some(took);
+    }
+
+    /// How long the sink took to collect its prebuffer the last time.
+    pub fn last_prime(&self) -> Option<Duration> {
+        *self.last_prime.lock().unwrap()
+    }
+
+    pub fn stall(&self) {
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stalls(&self) -> u64 {
+        self.stalls.load(Ordering::Relaxed)
+    }
+
+    /// Count a frame dropped because songbird fell behind.
+    pub fn dropped_frame(&self) {
+        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+
+    /// Count a voice connection that was restored, by !reconnect or after a failure.
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    /// Time spent streaming to voice since the bot started.
+    pub fn listening_time(&self) -> Duration {
+        let (listened, streaming_since) = *self.listening.lock().unwrap();
+
+        listened + streaming_since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
+
+    /// Counters of this run.
+    pub fn session(&self) -> LifetimeStats {
+        LifetimeStats {
+            tracks_played: self.tracks_played(),
+            listening_secs: self.listening_time().as_secs(),
+            reconnects: self.reconnects(),
+        }
+    }
+
+    /// Counters of this run added to those of previous runs.
+    pub fn lifetime(&self) -> LifetimeStats {
+        let previous = *self.previous.lock().unwrap();
+        let session = self.session();
+
+        LifetimeStats {
+            tracks_played: previous.tracks_played + session.tracks_played,
+            listening_secs: previous.listening_secs + session.listening_secs,
+            reconnects: previous.reconnects + session.reconnects,
+        }
+    }
+
+    /// Load the totals and guild volumes of previous runs from `path`, and save to
+    /// it from now on. A missing file starts from zero.
+    pub fn persist_to(&self, path: PathBuf) -> io::Result<()> {
+        let previous: StatsFile = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => StatsFile::default(),
+            Err(error) => return Err(error),
+        };
+
+        *self.previous.lock().unwrap() = previous.lifetime;
+        self.guild_volumes
+            .lock()
+            .unwrap()
+            .extend(previous.guild_volumes);
+        *self.path.lock().unwrap() = Some(path);
+
+        Ok(())
+    }
+
+    /// Write the lifetime counters and guild volumes to the file given to `persist_to`, if any.
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = self.path.lock().unwrap().clone() else {
+            return Ok(());
+        };
+
+        let file = StatsFile {
+            lifetime: self.lifetime(),
+            guild_volumes: self.guild_volumes.lock().unwrap().clone(),
+        };
+
+        // Write to a temporary file first so a crash can't leave a truncated file
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&file)?)?;
+        fs::rename(temporary, path)
+    }
+
+    /// Remember `volume` as the one to use in `guild_id`.
+    pub fn set_guild_volume(&self, guild_id: GuildId, volume: u16) {
+        self.guild_volumes
+            .lock()
+            .unwrap()
+            .insert(guild_id.0, volume);
+    }
+
+    /// Volume last used in `guild_id`, if the bot has streamed there before.
+    pub fn guild_volume(&self, guild_id: GuildId) -> Option<u16> {
+        self.guild_volumes.lock().unwrap().get(&guild_id.0).copied()
+    }
+
+    pub fn set_voice_channel(&self, channel: Option<(GuildId, ChannelId)>) {
+        *self.voice_channel.lock().unwrap() = channel;
+    }
+
+    pub fn voice_channel(&self) -> Option<(GuildId, ChannelId)> {
+        *self.voice_channel.lock().unwrap()
+    }
+
+    pub fn set_voice_status(&self, status: VoiceStatus) {
+        *self.voice_status.lock().unwrap() = status;
+
+        let mut listening = self.listening.lock().unwrap();
+
+        match (status, listening.1) {
+            (VoiceStatus::Streaming, None) => listening.1 = Some(Instant::now()),
+            (VoiceStatus::Idle | VoiceStatus::NotInVoice, Some(since)) => {
+                listening.0 += since.elapsed();
+                listening.1 = None;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn voice_status(&self) -> VoiceStatus {
+        *self.voice_status.lock().unwrap()
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    format!("{}h {}m {}s", secs / 3600, (secs / 60) % 60, secs % 60)
+}
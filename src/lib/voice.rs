@@ -0,0 +1,943 @@
+use super::announce::AnnouncerKey;
+use super::ducking::Ducker;
+use super::player::{EmittedSink, SpotifyPlayer, CHANNELS};
+use super::stats::VoiceStatus;
+use crate::ConfigKey;
+
+use serenity::async_trait;
+use serenity::client::Context;
+use serenity::model::channel::ChannelType;
+use serenity::model::guild::PremiumTier;
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use serenity::model::voice::VoiceState;
+
+use songbird::error::JoinError;
+use songbird::events::context_data::{DisconnectKind, DisconnectReason};
+use songbird::events::{
+    CoreEvent, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+use songbird::input;
+use songbird::tracks::{PlayMode, TrackHandle};
+
+use std::error::Error;
+use std::fmt;
+use std::process::exit;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+/// Wait this long before recovering from an unexpected track end,
+/// so a persistently failing connection doesn't spin.
+/// Doubled after every failed attempt.
+const RECOVERY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between two recovery attempts.
+const MAX_RECOVERY_DELAY: Duration = Duration::from_secs(60);
+
+/// Why the bot isn't streaming to a voice channel it was asked to join.
+#[derive(Debug)]
+pub enum VoiceError {
+    /// songbird couldn't join, or there is no call to reconnect
+    Join(JoinError),
+    /// `OUTPUT_SAMPLE_RATE` isn't the rate Discord plays, so the sink only feeds the recording
+    SampleRate(u32),
+    /// The channel is at its user limit and the bot can't bypass it
+    ChannelFull { user_limit: u64 },
+}
+
+impl fmt::Display for VoiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoiceError::Join(error) => write!(f, "{}", error),
+            VoiceError::SampleRate(rate) => write!(
+                f,
+                "OUTPUT_SAMPLE_RATE is {} Hz but Discord needs {} Hz",
+                rate,
+                songbird::constants::SAMPLE_RATE_RAW
+            ),
+            VoiceError::ChannelFull { user_limit } => {
+                write!(f, "the channel is full ({} users)", user_limit)
+            }
+        }
+    }
+}
+
+impl Error for VoiceError {}
+
+impl From<JoinError> for VoiceError {
+    fn from(error: JoinError) -> Self {
+        VoiceError::Join(error)
+    }
+}
+
+/// What to do about a voice state update.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoiceStateAction {
+    /// Another user, or nothing relevant changed (e.g. only mute or deafen)
+    Ignore,
+    /// The user joined a voice channel
+    Joined,
+    /// The user left voice in `guild_id`, if known
+    Left { guild_id: Option<GuildId> },
+    /// The user moved to `channel_id`. `old_guild_id` isn't always sent on the first move
+    Moved {
+        old_guild_id: Option<GuildId>,
+        guild_id: Option<GuildId>,
+        channel_id: ChannelId,
+    },
+}
+
+/// Decide how to react to a voice state update from `old` to `new`, given the
+/// user the bot follows and the channel the bot streams to.
+///
+/// Only joining, leaving and moving between channels affect playback. Muting,
+/// deafening or being server-muted keeps the bot streaming, the user may still be listening.
+pub fn voice_state_action(
+    old: Option<&VoiceState>,
+    new: &VoiceState,
+    user_id: UserId,
+    bot_channel_id: Option<ChannelId>,
+) -> VoiceStateAction {
+    if new.user_id != user_id {
+        return VoiceStateAction::Ignore;
+    }
+
+    let old_channel_id = old.and_then(|old| old.channel_id);
+
+    match (old_channel_id, new.channel_id) {
+        // Without a cached old state a mute looks like joining, but the bot is already there
+        (None, Some(channel_id)) if bot_channel_id == Some(channel_id) => VoiceStateAction::Ignore,
+        (None, Some(_)) => VoiceStateAction::Joined,
+        (Some(_), None) => VoiceStateAction::Left {
+            guild_id: new.guild_id.or(old.and_then(|old| old.guild_id)),
+        },
+        (Some(old_channel_id), Some(channel_id)) if old_channel_id != channel_id => {
+            VoiceStateAction::Moved {
+                old_guild_id: old.and_then(|old| old.guild_id),
+                guild_id: new.guild_id,
+                channel_id,
+            }
+        }
+        // Same channel: mute, deafen, video or stream changes
+        _ => VoiceStateAction::Ignore,
+    }
+}
+
+/// Highest voice bitrate Discord allows in a guild of the given boost tier, in bit/s.
+fn max_bitrate(tier: PremiumTier) -> u64 {
+    match tier {
+        PremiumTier::Tier1 => 128_000,
+        PremiumTier::Tier2 => 256_000,
+        PremiumTier::Tier3 => 384_000,
+        _ => 96_000,
+    }
+}
+
+/// Bitrate to encode at in `channel_id`: `VOICE_BITRATE`, or the channel's bitrate,
+/// capped at what the guild's boost tier allows.
+async fn voice_bitrate(ctx: &Context, guild_id: GuildId, channel_id: ChannelId) -> u64 {
+    let configured = ctx
+        .data
+        .read()
+        .await
+        .get::<ConfigKey>()
+        .unwrap()
+        .voice_bitrate;
+
+    let (cap, channel_bitrate) = match ctx.cache.guild(guild_id) {
+        Some(guild) => (
+            max_bitrate(guild.premium_tier),
+            guild
+                .channels
+                .get(&channel_id)
+                .and_then(|channel| channel.clone().guild())
+                .and_then(|channel| channel.bitrate),
+        ),
+        None => (max_bitrate(PremiumTier::Tier0), None),
+    };
+
+    configured.or(channel_bitrate).unwrap_or(cap).min(cap)
+}
+
+/// Bots join Stage channels as audience, so become a speaker to be heard.
+/// Without the Mute Members permission only a request to speak is possible,
+/// which a stage moderator has to accept.
+async fn become_speaker(ctx: &Context, channel_id: ChannelId) {
+    let channel = match ctx.cache.guild_channel(channel_id) {
+        Some(channel) if channel.kind == ChannelType::Stage => channel,
+        _ => return,
+    };
+
+    if let Err(error) = channel
+        .edit_own_voice_state(&ctx.http, |v| v.suppress(false))
+        .await
+    {
+        tracing::warn!(
+            "Could not become a speaker in stage {}, requesting to speak instead: {:?}",
+            channel.name,
+            error
+        );
+
+        if let Err(error) = channel
+            .edit_own_voice_state(&ctx.http, |v| v.request_to_speak(true))
+            .await
+        {
+            tracing::warn!(
+                "Could not request to speak in stage {}: {:?}",
+                channel.name,
+                error
+            );
+        }
+    }
+}
+
+/// The user limit of `channel_id` if it's reached and the bot can't join anyway.
+fn channel_full(ctx: &Context, guild_id: GuildId, channel_id: ChannelId) -> Option<u64> {
+    let channel = ctx.cache.guild_channel(channel_id)?;
+    // Stages have an audience instead of a limit that keeps speakers out
+    if channel.kind != ChannelType::Voice {
+        return None;
+    }
+    let user_limit = channel.user_limit.filter(|user_limit| *user_limit > 0)?;
+
+    let bot_id = ctx.cache.current_user_id();
+
+    // Members who can move others may join full channels
+    if channel
+        .permissions_for_user(&ctx.cache, bot_id)
+        .is_ok_and(|permissions| permissions.move_members())
+    {
+        return None;
+    }
+
+    let members = ctx
+        .cache
+        .guild(guild_id)?
+        .voice_states
+        .values()
+        .filter(|voice_state| {
+            voice_state.channel_id == Some(channel_id) && voice_state.user_id != bot_id
+        })
+        .count() as u64;
+
+    (members >= user_limit).then_some(user_limit)
+}
+
+/// Whether our songbird track is currently playing in `channel_id`.
+async fn streaming_to(player: &Arc<Mutex<SpotifyPlayer>>, channel_id: ChannelId) -> bool {
+    let player = player.lock().await;
+
+    let current_channel_id = player.stats.voice_channel().map(|(_, current)| current);
+    let play_mode = match player.track_handle.as_ref() {
+        Some(track_handle) => track_handle
+            .get_info()
+            .await
+            .ok()
+            .map(|state| state.playing),
+        None => None,
+    };
+
+    already_streaming(current_channel_id, play_mode, channel_id)
+}
+
+/// Whether our track, playing as `play_mode` in `current_channel_id`, already streams
+/// to `channel_id`, so a repeated Started must not start a second source.
+fn already_streaming(
+    current_channel_id: Option<ChannelId>,
+    play_mode: Option<PlayMode>,
+    channel_id: ChannelId,
+) -> bool {
+    current_channel_id == Some(channel_id) && matches!(play_mode, Some(PlayMode::Play))
+}
+
+/// Join `channel_id` and start streaming the Spotify sink into it.
+pub async fn join_and_play(
+    ctx: &Context,
+    player: &Arc<Mutex<SpotifyPlayer>>,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+) -> Result<(), VoiceError> {
+    let output_rate = player.lock().await.emitted_sink.output_rate;
+
+    // songbird doesn't resample, so anything but 48 kHz would play at the wrong speed
+    if output_rate != songbird::constants::SAMPLE_RATE_RAW as u32 {
+        return Err(VoiceError::SampleRate(output_rate));
+    }
+
+    // Started can arrive twice in a row when the player is rebuilt. Streaming a second
+    // source into the same channel would double the audio, so keep the one that's playing
+    if streaming_to(player, channel_id).await {
+        tracing::debug!("Already streaming to voice channel {}", channel_id);
+        return Ok(());
+    }
+
+    // Discord would refuse the bot without telling songbird, so the join would time out
+    if let Some(user_limit) = channel_full(ctx, guild_id, channel_id) {
+        return Err(VoiceError::ChannelFull { user_limit });
+    }
+
+    let manager = songbird::get(ctx)
+        .await
+        .expect("Songbird Voice client placed in at initialization.")
+        .clone();
+
+    let (handler_lock, join_result) = manager.join(guild_id, channel_id).await;
+    join_result?;
+
+    become_speaker(ctx, channel_id).await;
+
+    {
+        let mut player = player.lock().await;
+        player.stats.set_voice_channel(Some((guild_id, channel_id)));
+        player.use_guild_volume(guild_id);
+    }
+    announce_voice(ctx, Some(channel_id)).await;
+
+    let (warmup, rejoin, ducking) = {
+        let data = ctx.data.read().await;
+        let config = data.get::<ConfigKey>().unwrap();
+        (
+            config.warmup,
+            config.voice_rejoin,
+            config
+                .duck_level
+                .map(|level| (level, Duration::from_millis(config.duck_release_ms))),
+        )
+    };
+
+    let sink = {
+        let mut player = player.lock().await;
+        let player = &mut *player;
+        next_reader(&mut player.track_handle, &player.emitted_sink)
+    };
+
+    if warmup && !sink.wait_for_prebuffer().await {
+        tracing::debug!("Prebuffer not filled yet, streaming anyway");
+    }
+
+    let mut handler = handler_lock.lock().await;
+
+    // A kept handler still has the events of the last join
+    handler.remove_all_global_events();
+    sink.duck_gain().fade_to(1.0);
+
+    if let Some((level, release)) = ducking {
+        let ducker = Ducker::new(sink.duck_gain(), level, release, ctx.cache.clone());
+        handler.add_global_event(CoreEvent::SpeakingStateUpdate.into(), ducker.clone());
+        handler.add_global_event(CoreEvent::SpeakingUpdate.into(), ducker);
+    }
+
+    if rejoin {
+        handler.add_global_event(
+            CoreEvent::DriverDisconnect.into(),
+            DisconnectHandler {
+                ctx: ctx.clone(),
+                player: player.clone(),
+            },
+        );
+    }
+
+    let mut decoder = input::codec::OpusDecoderState::new().unwrap();
+    decoder.allow_passthrough = false;
+
+    let source = sink_source(sink, output_rate);
+
+    let bitrate = voice_bitrate(ctx, guild_id, channel_id).await;
+    tracing::info!(
+        guild_id = %guild_id,
+        channel_id = %channel_id,
+        "Streaming at {} kbit/s",
+        bitrate / 1000
+    );
+    handler.set_bitrate(songbird::driver::Bitrate::BitsPerSecond(bitrate as i32));
+
+    // play_only_source stops the other tracks of this call, ours is already forgotten
+    let track_handle = handler.play_only_source(source);
+
+    // Spotify Connect volume is already applied to the PCM in the sink,
+    // so keep songbird's gain neutral for the two not to multiply
+    let _ = track_handle.set_volume(1.0);
+
+    let _ = track_handle.add_event(
+        Event::Track(TrackEvent::End),
+        TrackEndHandler {
+            ctx: ctx.clone(),
+            player: player.clone(),
+        },
+    );
+
+    let mut player = player.lock().await;
+    player.track_handle = Some(track_handle);
+    player.stats.set_voice_status(VoiceStatus::Streaming);
+
+    Ok(())
+}
+
+/// A reader of `sink` for a new songbird track. The current track is forgotten first:
+/// the new reader ends it, which `TrackEndHandler` mustn't take for a failure.
+fn next_reader(track_handle: &mut Option<TrackHandle>, sink: &EmittedSink) -> EmittedSink {
+    *track_handle = None;
+    sink.reader()
+}
+
+/// The songbird input streaming `sink` at `output_rate`.
+fn sink_source(sink: EmittedSink, output_rate: u32) -> input::Input {
+    let codec = sink.sample_format.codec();
+
+    input::Input::new(
+        CHANNELS == 2,
+        input::reader::Reader::Extension(Box::new(sink)),
+        codec,
+        input::Container::Raw,
+        Some(input::Metadata {
+            channels: Some(CHANNELS as u8),
+            sample_rate: Some(output_rate),
+            ..Default::default()
+        }),
+    )
+}
+
+/// Whether `ended` is the track currently streaming, so its end is a failure.
+fn is_current_track(current: Option<&TrackHandle>, ended: &TrackHandle) -> bool {
+    current.is_some_and(|current| current.uuid() == ended.uuid())
+}
+
+/// Pause the songbird track while Spotify is paused, so it doesn't keep polling the sink.
+pub async fn pause(player: &Arc<Mutex<SpotifyPlayer>>) {
+    let player = player.lock().await;
+
+    if let Some(track_handle) = player.track_handle.as_ref() {
+        let _ = track_handle.pause();
+        player.stats.set_voice_status(VoiceStatus::Idle);
+    }
+}
+
+/// Stop streaming but stay in the voice channel, ready for the next play.
+pub async fn stop(player: &Arc<Mutex<SpotifyPlayer>>) {
+    let mut player = player.lock().await;
+
+    // Forget the track first, so its end isn't treated as a failure
+    if let Some(track_handle) = player.track_handle.take() {
+        let _ = track_handle.stop();
+        player.stats.set_voice_status(VoiceStatus::Idle);
+    }
+}
+
+/// Resume the existing songbird track in `channel_id`, if there is one.
+/// Returns whether a track was resumed.
+pub async fn resume(player: &Arc<Mutex<SpotifyPlayer>>, channel_id: Option<ChannelId>) -> bool {
+    let player = player.lock().await;
+
+    let in_channel = match channel_id {
+        Some(channel_id) => player
+            .stats
+            .voice_channel()
+            .map(|(_, current)| current == channel_id)
+            .unwrap_or(false),
+        None => true,
+    };
+
+    let resumed = match player.track_handle.as_ref() {
+        Some(track_handle) if in_channel => track_handle.play().is_ok(),
+        _ => false,
+    };
+
+    if resumed {
+        player.stats.set_voice_status(VoiceStatus::Streaming);
+    }
+
+    resumed
+}
+
+/// Post to the announce channel that the bot joined `channel_id` or left voice, with `ANNOUNCE_VOICE`.
+async fn announce_voice(ctx: &Context, channel_id: Option<ChannelId>) {
+    let data = ctx.data.read().await;
+
+    if data.get::<ConfigKey>().unwrap().announce_voice {
+        data.get::<AnnouncerKey>()
+            .unwrap()
+            .voice_channel(ctx.http.clone(), channel_id);
+    }
+}
+
+/// Stop streaming and leave the voice channel in `guild_id`.
+pub async fn leave(ctx: &Context, player: &Arc<Mutex<SpotifyPlayer>>, guild_id: GuildId) {
+    {
+        let mut player = player.lock().await;
+        // Forget the track first, so its end isn't treated as a failure
+        if let Some(track_handle) = player.track_handle.take() {
+            // A kept handler would otherwise keep reading the sink
+            let _ = track_handle.stop();
+        }
+        player.stats.set_voice_channel(None);
+        player.stats.set_voice_status(VoiceStatus::NotInVoice);
+        player.remember_guild_volume();
+    }
+
+    announce_voice(ctx, None).await;
+
+    let keep_handler = ctx
+        .data
+        .read()
+        .await
+        .get::<ConfigKey>()
+        .unwrap()
+        .keep_voice_handlers;
+
+    let manager = songbird::get(ctx)
+        .await
+        .expect("Songbird Voice client placed in at initialization.")
+        .clone();
+
+    // Leaving keeps the guild's handler and its driver around for the next join,
+    // removing it frees them
+    if keep_handler {
+        let _ = manager.leave(guild_id).await;
+    } else {
+        let _ = manager.remove(guild_id).await;
+    }
+}
+
+/// Leave and rejoin the voice channel we're streaming to, restarting the stream.
+/// Fails with `JoinError::NoCall` if we aren't streaming anywhere.
+pub async fn reconnect(
+    ctx: &Context,
+    player: &Arc<Mutex<SpotifyPlayer>>,
+) -> Result<(), VoiceError> {
+    let Some((guild_id, channel_id)) = player.lock().await.stats.voice_channel() else {
+        return Err(JoinError::NoCall.into());
+    };
+
+    tracing::info!(guild_id = %guild_id, channel_id = %channel_id, "Reconnecting to voice channel");
+
+    leave(ctx, player, guild_id).await;
+
+    join_and_play(ctx, player, guild_id, channel_id).await?;
+    player.lock().await.stats.reconnected();
+
+    Ok(())
+}
+
+/// Reconnect after the stream failed, retrying with backoff up to `MAX_RECONNECT_ATTEMPTS` times.
+async fn recover(ctx: &Context, player: &Arc<Mutex<SpotifyPlayer>>) {
+    let (max_attempts, exit_on_failure) = {
+        let data = ctx.data.read().await;
+        let config = data.get::<ConfigKey>().unwrap();
+        (
+            config.max_reconnect_attempts,
+            config.exit_on_reconnect_failure,
+        )
+    };
+
+    // A failed attempt leaves the channel, so remember where to rejoin
+    let (guild_id, channel_id) = {
+        let mut player = player.lock().await;
+
+        // One failure can end the track and disconnect the driver, recover only once
+        if player.recovering {
+            tracing::debug!("Already recovering voice playback");
+            return;
+        }
+
+        let Some(voice_channel) = player.stats.voice_channel() else {
+            return;
+        };
+        player.recovering = true;
+        voice_channel
+    };
+
+    crate::reconnecting(&ctx.data, &ctx.http, "voice").await;
+
+    let recovered = rejoin(ctx, player, guild_id, channel_id, max_attempts).await;
+    player.lock().await.recovering = false;
+
+    crate::reconnected(&ctx.data, &ctx.http, "voice", recovered).await;
+
+    if !recovered && exit_on_failure {
+        tracing::error!("Exiting so the supervisor can restart the bot");
+        exit(1);
+    }
+}
+
+/// Leave and join `channel_id` again until streaming works, backing off between
+/// attempts. Returns false after `max_attempts` failed.
+async fn rejoin(
+    ctx: &Context,
+    player: &Arc<Mutex<SpotifyPlayer>>,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    max_attempts: Option<u32>,
+) -> bool {
+    let mut delay = RECOVERY_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        sleep(delay).await;
+
+        tracing::info!(guild_id = %guild_id, channel_id = %channel_id, "Reconnecting to voice channel");
+
+        leave(ctx, player, guild_id).await;
+
+        let Err(error) = join_and_play(ctx, player, guild_id, channel_id).await else {
+            player.lock().await.stats.reconnected();
+            return true;
+        };
+
+        if max_attempts.is_some_and(|max_attempts| attempt >= max_attempts) {
+            tracing::error!(
+                "Could not recover voice playback after {} attempts, giving up: {}",
+                attempt,
+                error
+            );
+            return false;
+        }
+
+        tracing::warn!(
+            "Could not recover voice playback (attempt {}): {}, retrying",
+            attempt,
+            error
+        );
+
+        delay = (delay * 2).min(MAX_RECOVERY_DELAY);
+        attempt += 1;
+    }
+}
+
+/// Restarts the stream when songbird ends the track we're currently streaming.
+/// Our source never ends on its own, so this means playback failed on songbird's side.
+struct TrackEndHandler {
+    ctx: Context,
+    player: Arc<Mutex<SpotifyPlayer>>,
+}
+
+#[async_trait]
+impl VoiceEventHandler for TrackEndHandler {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let EventContext::Track(tracks) = ctx else {
+            return None;
+        };
+
+        for (state, handle) in tracks.iter() {
+            let is_current =
+                is_current_track(self.player.lock().await.track_handle.as_ref(), handle);
+
+            if !is_current {
+                continue;
+            }
+
+            tracing::warn!(
+                "Voice track ended unexpectedly ({:?}), recovering",
+                state.playing
+            );
+
+            let ctx = self.ctx.clone();
+            let player = self.player.clone();
+            tokio::spawn(async move {
+                recover(&ctx, &player).await;
+            });
+        }
+
+        None
+    }
+}
+
+/// Rejoins when the voice connection drops while streaming, most commonly because Discord
+/// invalidated the voice session (close code 4006). songbird needs a new session from the
+/// gateway for that, which only a full leave and join requests.
+struct DisconnectHandler {
+    ctx: Context,
+    player: Arc<Mutex<SpotifyPlayer>>,
+}
+
+#[async_trait]
+impl VoiceEventHandler for DisconnectHandler {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let EventContext::DriverDisconnect(disconnect) = ctx else {
+            return None;
+        };
+
+        // No reason means we left or moved ourselves, and failing to connect
+        // in the first place is already handled by whoever joined
+        let reason = disconnect.reason?;
+        if disconnect.kind == DisconnectKind::Connect {
+            return None;
+        }
+
+        let guild_id = GuildId(disconnect.guild_id.0);
+        let streaming = self
+            .player
+            .lock()
+            .await
+            .stats
+            .voice_channel()
+            .is_some_and(|(streaming_guild_id, _)| streaming_guild_id == guild_id);
+
+        if !streaming {
+            return None;
+        }
+
+        let error = match reason {
+            DisconnectReason::WsClosed(Some(code)) => {
+                format!("closed with {} ({:?})", code as u16, code)
+            }
+            reason => format!("{:?}", reason),
+        };
+
+        tracing::warn!(
+            guild_id = %guild_id,
+            error = %error,
+            "Voice connection lost during {:?}, rejoining",
+            disconnect.kind
+        );
+
+        let ctx = self.ctx.clone();
+        let player = self.player.clone();
+        tokio::spawn(async move {
+            recover(&ctx, &player).await;
+        });
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lib::player::tests::sink;
+    use crate::lib::player::SampleFormat;
+
+    use songbird::tracks::create_player;
+
+    const OUTPUT_RATE: usize = songbird::constants::SAMPLE_RATE_RAW;
+
+    const USER: UserId = UserId(1);
+    const OTHER_USER: UserId = UserId(2);
+    const GUILD: GuildId = GuildId(10);
+    const CHANNEL: ChannelId = ChannelId(100);
+    const OTHER_CHANNEL: ChannelId = ChannelId(200);
+
+    fn voice_state(user_id: UserId, channel_id: Option<ChannelId>) -> VoiceState {
+        serde_json::from_value(serde_json::json!({
+            "channel_id": channel_id,
+            "deaf": false,
+            "guild_id": GUILD,
+            "mute": false,
+            "self_deaf": false,
+            "self_mute": false,
+            "self_video": false,
+            "session_id": "session",
+            "suppress": false,
+            "user_id": user_id,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn joining_from_nowhere() {
+        let new = voice_state(USER, Some(CHANNEL));
+
+        assert_eq!(
+            voice_state_action(None, &new, USER, None),
+            VoiceStateAction::Joined
+        );
+
+        let old = voice_state(USER, None);
+        assert_eq!(
+            voice_state_action(Some(&old), &new, USER, None),
+            VoiceStateAction::Joined
+        );
+    }
+
+    #[test]
+    fn leaving_voice() {
+        let old = voice_state(USER, Some(CHANNEL));
+        let new = voice_state(USER, None);
+
+        assert_eq!(
+            voice_state_action(Some(&old), &new, USER, Some(CHANNEL)),
+            VoiceStateAction::Left {
+                guild_id: Some(GUILD)
+            }
+        );
+    }
+
+    #[test]
+    fn no_channel_before_or_after() {
+        let old = voice_state(USER, None);
+        let new = voice_state(USER, None);
+
+        assert_eq!(
+            voice_state_action(Some(&old), &new, USER, None),
+            VoiceStateAction::Ignore
+        );
+        assert_eq!(
+            voice_state_action(None, &new, USER, None),
+            VoiceStateAction::Ignore
+        );
+    }
+
+    #[test]
+    fn mute_and_deafen_keep_streaming() {
+        let old = voice_state(USER, Some(CHANNEL));
+
+        let mut muted = voice_state(USER, Some(CHANNEL));
+        muted.self_mute = true;
+        assert_eq!(
+            voice_state_action(Some(&old), &muted, USER, Some(CHANNEL)),
+            VoiceStateAction::Ignore
+        );
+
+        let mut deafened = voice_state(USER, Some(CHANNEL));
+        deafened.self_deaf = true;
+        assert_eq!(
+            voice_state_action(Some(&old), &deafened, USER, Some(CHANNEL)),
+            VoiceStateAction::Ignore
+        );
+
+        let mut server_muted = voice_state(USER, Some(CHANNEL));
+        server_muted.mute = true;
+        assert_eq!(
+            voice_state_action(Some(&old), &server_muted, USER, Some(CHANNEL)),
+            VoiceStateAction::Ignore
+        );
+    }
+
+    #[test]
+    fn same_channel_is_ignored() {
+        let old = voice_state(USER, Some(CHANNEL));
+        let new = voice_state(USER, Some(CHANNEL));
+
+        assert_eq!(
+            voice_state_action(Some(&old), &new, USER, None),
+            VoiceStateAction::Ignore
+        );
+    }
+
+    #[test]
+    fn moving_to_another_channel() {
+        let old = voice_state(USER, Some(CHANNEL));
+        let new = voice_state(USER, Some(OTHER_CHANNEL));
+
+        assert_eq!(
+            voice_state_action(Some(&old), &new, USER, Some(CHANNEL)),
+            VoiceStateAction::Moved {
+                old_guild_id: Some(GUILD),
+                guild_id: Some(GUILD),
+                channel_id: OTHER_CHANNEL,
+            }
+        );
+    }
+
+    #[test]
+    fn other_users_are_ignored() {
+        let old = voice_state(OTHER_USER, Some(CHANNEL));
+        let new = voice_state(OTHER_USER, Some(OTHER_CHANNEL));
+
+        assert_eq!(
+            voice_state_action(Some(&old), &new, USER, Some(CHANNEL)),
+            VoiceStateAction::Ignore
+        );
+        assert_eq!(
+            voice_state_action(None, &new, USER, None),
+            VoiceStateAction::Ignore
+        );
+        assert_eq!(
+            voice_state_action(Some(&old), &voice_state(OTHER_USER, None), USER, None),
+            VoiceStateAction::Ignore
+        );
+    }
+
+    #[test]
+    fn uncached_update_in_the_bots_channel() {
+        // Without the old state a mute in the bot's channel looks like joining
+        let mut muted = voice_state(USER, Some(CHANNEL));
+        muted.self_mute = true;
+
+        assert_eq!(
+            voice_state_action(None, &muted, USER, Some(CHANNEL)),
+            VoiceStateAction::Ignore
+        );
+        assert_eq!(
+            voice_state_action(None, &muted, USER, Some(OTHER_CHANNEL)),
+            VoiceStateAction::Joined
+        );
+    }
+
+    #[test]
+    fn repeated_started_keeps_the_playing_source() {
+        assert!(already_streaming(
+            Some(CHANNEL),
+            Some(PlayMode::Play),
+            CHANNEL
+        ));
+    }
+
+    #[test]
+    fn started_joins_when_not_streaming_there() {
+        // Another channel
+        assert!(!already_streaming(
+            Some(OTHER_CHANNEL),
+            Some(PlayMode::Play),
+            CHANNEL
+        ));
+        // Not in voice, or no track
+        assert!(!already_streaming(None, Some(PlayMode::Play), CHANNEL));
+        assert!(!already_streaming(Some(CHANNEL), None, CHANNEL));
+        // The track stopped or ended, e.g. after the connection failed
+        assert!(!already_streaming(
+            Some(CHANNEL),
+            Some(PlayMode::Stop),
+            CHANNEL
+        ));
+        assert!(!already_streaming(
+            Some(CHANNEL),
+            Some(PlayMode::End),
+            CHANNEL
+        ));
+        assert!(!already_streaming(
+            Some(CHANNEL),
+            Some(PlayMode::Pause),
+            CHANNEL
+        ));
+    }
+
+    #[test]
+    fn moving_doesnt_recover() {
+        let sink = sink(OUTPUT_RATE, SampleFormat::F32);
+        let (_, old_track) = create_player(sink_source(sink.reader(), OUTPUT_RATE as u32));
+        let mut track_handle = Some(old_track.clone());
+
+        // Joining the new channel hands songbird a new reader, ending the old track
+        let _new_reader = next_reader(&mut track_handle, &sink);
+
+        assert!(!is_current_track(track_handle.as_ref(), &old_track));
+    }
+
+    #[test]
+    fn only_the_current_track_recovers() {
+        let sink = sink(OUTPUT_RATE, SampleFormat::F32);
+        let (_, track) = create_player(sink_source(sink.reader(), OUTPUT_RATE as u32));
+        let (_, other_track) = create_player(sink_source(sink.reader(), OUTPUT_RATE as u32));
+
+        assert!(is_current_track(Some(&track), &track));
+        assert!(!is_current_track(Some(&other_track), &track));
+        assert!(!is_current_track(None, &track));
+    }
+
+    #[test]
+    fn moving_away_from_the_bot() {
+        let old = voice_state(USER, Some(CHANNEL));
+        let new = voice_state(USER, Some(OTHER_CHANNEL));
+
+        // Where the bot streams doesn't matter once the old channel is known
+        assert_eq!(
+            voice_state_action(Some(&old), &new, USER, Some(OTHER_CHANNEL)),
+            VoiceStateAction::Moved {
+                old_guild_id: Some(GUILD),
+                guild_id: Some(GUILD),
+                channel_id: OTHER_CHANNEL,
+            }
+        );
+    }
+}
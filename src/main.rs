@@ -1,64 +1,729 @@
+use std::collections::HashSet;
 use std::env;
+use std::mem;
 use std::process::exit;
 
-use lib::config::Config;
-use songbird::{input, SerenityInit};
+use lib::config::{ActivityType, Config, LogFormat, SessionStolenAction, ShardCount};
+use songbird::SerenityInit;
 
 mod lib {
+    pub mod adaptive;
+    pub mod announce;
+    pub mod commands;
     pub mod config;
+    pub mod devices;
+    pub mod ducking;
+    pub mod events;
+    pub mod follow;
+    pub mod loudness;
+    pub mod lyrics;
+    pub mod metadata;
+    pub mod now_playing;
+    pub mod play;
     pub mod player;
+    pub mod recorder;
+    pub mod skip;
+    pub mod stats;
+    pub mod voice;
 }
 use figment::error::Kind::MissingField;
-use lib::player::{SpotifyPlayer, SpotifyPlayerKey};
+use lib::announce::{Announcer, AnnouncerKey};
+use lib::devices::ConnectDevice;
+use lib::follow::{Follower, FollowerKey};
+use lib::metadata::{MetadataCache, MetadataCacheKey, TrackDetails};
+use lib::play::PlayTarget;
+use lib::player::{BufferSettings, SpotifyPlayer, SpotifyPlayerKey};
+use lib::recorder::WavRecorder;
+use lib::skip::Skipper;
+use lib::stats::VoiceStatus;
+use lib::voice::{voice_state_action, VoiceError, VoiceStateAction};
 use librespot::core::mercury::MercuryError;
+use librespot::core::spotify_id::SpotifyId;
 use librespot::playback::config::Bitrate;
 use librespot::playback::player::PlayerEvent;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration};
+use tracing_subscriber::EnvFilter;
 
-use serenity::Client;
+use serenity::client::bridge::gateway::ShardManager;
+use serenity::client::ClientBuilder;
+use serenity::http::{Http, HttpBuilder};
 
-use serenity::prelude::TypeMapKey;
+use serenity::prelude::{RwLock, TypeMap, TypeMapKey};
 
 use serenity::{
     async_trait,
     client::{Context, EventHandler},
     framework::StandardFramework,
-    model::{gateway, gateway::Ready, id, user, voice::VoiceState},
+    model::{event::ResumedEvent, gateway, gateway::Ready, id, user, voice::VoiceState},
+    utils,
 };
 
-struct Handler;
+/// Wait this long after playback stopped before restarting it in radio mode.
+const RADIO_RESTART_DELAY: Duration = Duration::from_secs(2);
+
+/// Wait this long after playback stopped before checking whether another device took over.
+const TAKEOVER_CHECK_DELAY: Duration = Duration::from_millis(500);
+
+/// Underruns this early in a track count as startup stutter, see `log_track_start`.
+const STARTUP_WINDOW: Duration = Duration::from_secs(5);
+
+/// Save the lifetime stats this often, so a crash loses at most this much.
+const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often to check whether Spotify closed the session.
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keep playing after the context ran out and autoplay didn't pick up, called
+/// `RADIO_RESTART_DELAY` after that. At the end of a context Spirc pauses on its
+/// first track, otherwise playback `stopped`. Returns whether playback was restarted.
+async fn restart_radio(player: &Arc<Mutex<SpotifyPlayer>>, stopped: bool) -> bool {
+    let player = player.lock().await;
+
+    // Don't take playback back from a device it was moved to
+    if player.devices.other_device_active() {
+        return false;
+    }
+
+    let Some(spirc) = player.spirc.as_ref() else {
+        return false;
+    };
+
+    tracing::info!("Radio mode: playback ended, restarting it");
+
+    // Spirc ignores play while stopped, but next loads and plays the queue again
+    if stopped {
+        spirc.next();
+    } else {
+        spirc.play();
+    }
+
+    true
+}
+
+/// Log how long the buffer took to fill and whether it ran dry in the first
+/// `STARTUP_WINDOW` of `track_id`, for tuning `AUDIO_LATENCY_PROFILE` and `PREBUFFER_MS`.
+async fn log_track_start(player: Arc<Mutex<SpotifyPlayer>>, track_id: SpotifyId) {
+    let (stats, diagnostics) = {
+        let player = player.lock().await;
+        (player.stats.clone(), player.emitted_sink.diagnostics())
+    };
+
+    if stats.voice_status() != VoiceStatus::Streaming {
+        return;
+    }
+
+    let underruns = stats.underruns();
+    sleep(STARTUP_WINDOW).await;
+
+    // Another track started in the meantime, its underruns would be counted here
+    if stats.current_track() != Some(track_id) {
+        return;
+    }
+
+    let prebuffer_ms = diagnostics.prebuffer as u64 * 1000 / diagnostics.output_rate as u64;
+    let startup_underruns = stats.underruns() - underruns;
+
+    tracing::info!(
+        track_uri = %track_id.to_uri().unwrap_or_default(),
+        prime_ms = stats.last_prime().map(|took| took.as_millis() as u64),
+        prebuffer_ms,
+        startup_underruns,
+        "Track started, {} underruns in the first {:?}",
+        startup_underruns,
+        STARTUP_WINDOW
+    );
+}
+
+/// Spirc stops when another device of the account takes over playback.
+/// Returns that device, if there is one.
+async fn taken_over_by(player: &Arc<Mutex<SpotifyPlayer>>) -> Option<ConnectDevice> {
+    // The device tracker may not have seen the other device's announcement yet
+    sleep(TAKEOVER_CHECK_DELAY).await;
+
+    player.lock().await.devices.active_device()
+}
+
+/// Handle playback having stopped: restart it in radio mode, or find out whether another
+/// device took over and act on it, then leave or stop streaming. Does nothing once
+/// `stop_generation` moved on from `generation`, playback started again.
+async fn playback_stopped(
+    c: Context,
+    player: Arc<Mutex<SpotifyPlayer>>,
+    announcer: Announcer,
+    config: Config,
+    stop_generation: Arc<AtomicU64>,
+    generation: u64,
+) {
+    if config.radio_mode {
+        sleep(RADIO_RESTART_DELAY).await;
+
+        if stop_generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        if restart_radio(&player, true).await {
+            return;
+        }
+    }
+
+    let taken_over_by = taken_over_by(&player).await;
+
+    // Playback started again in the meantime
+    if stop_generation.load(Ordering::SeqCst) != generation {
+        return;
+    }
+
+    if let Some(device) = taken_over_by.as_ref() {
+        if config.on_session_stolen == SessionStolenAction::Reclaim {
+            tracing::info!("{} took over playback, moving it back", device.name);
+
+            match player.lock().await.devices.reclaim(device) {
+                Ok(()) => return,
+                Err(error) => {
+                    tracing::warn!("Could not reclaim playback: {}", error)
+                }
+            }
+        }
+
+        tracing::info!("Playback moved to {}", device.name);
+        announcer.say(
+            c.http.clone(),
+            format!("Spotify playback moved to {}.", device.name),
+        );
+    }
+
+    // Without an announce channel, tell the user through the presence
+    match taken_over_by.as_ref() {
+        Some(device) if !announcer.enabled() => {
+            set_presence(
+                &c,
+                Some(gateway::Activity::playing(format!(
+                    "Paused, playing on {}",
+                    device.name
+                ))),
+                user::OnlineStatus::Idle,
+            )
+            .await
+        }
+        _ => set_presence(&c, config.idle_activity(), user::OnlineStatus::Online).await,
+    }
+    announcer.reset();
+
+    let leave = match taken_over_by {
+        Some(_) => config.on_session_stolen == SessionStolenAction::Leave,
+        None => config.leave_on_stop,
+    };
+
+    if leave && config.stop_grace_secs > 0 {
+        // Playing something else right away shouldn't leave and rejoin
+        let generation = stop_generation.load(Ordering::SeqCst);
+        let current_generation = stop_generation;
+        let grace = Duration::from_secs(config.stop_grace_secs);
+        let (c, player) = (c.clone(), player.clone());
+
+        lib::voice::stop(&player).await;
+
+        tokio::spawn(async move {
+            sleep(grace).await;
+
+            if current_generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+
+            let config = c.data.read().await.get::<ConfigKey>().unwrap().clone();
+            for guild_id in target_guilds(&c, &config) {
+                lib::voice::leave(&c, &player, guild_id).await;
+            }
+        });
+    } else if leave {
+        for guild_id in target_guilds(&c, &config) {
+            lib::voice::leave(&c, &player, guild_id).await;
+        }
+    } else {
+        lib::voice::stop(&player).await;
+    }
+}
+
+/// Restart the voice stream after the gateway reconnected, if we were streaming.
+async fn revalidate_voice(ctx: &Context) {
+    let player = ctx
+        .data
+        .read()
+        .await
+        .get::<SpotifyPlayerKey>()
+        .unwrap()
+        .clone();
+
+    if player.lock().await.stats.voice_channel().is_none() {
+        return;
+    }
+
+    tracing::info!("Gateway reconnected, re-validating voice connection");
+
+    if let Err(error) = lib::voice::reconnect(ctx, &player).await {
+        tracing::error!("Could not restore voice connection: {}", error);
+    }
+}
+
+/// Re-read the config and apply the settings that can change without a restart.
+pub async fn reload_config(ctx: &Context) -> Result<(), String> {
+    let fresh = Config::new().map_err(|error| error.to_string())?;
+
+    let (player, previous, config) = {
+        let mut data = ctx.data.write().await;
+
+        let previous = data.get::<ConfigKey>().unwrap().clone();
+        let config = previous.reloaded(fresh);
+        data.get::<AnnouncerKey>()
+            .unwrap()
+            .set_channel_id(config.announce_channel_id);
+        data.insert::<ConfigKey>(config.clone());
+
+        (
+            data.get::<SpotifyPlayerKey>().unwrap().clone(),
+            previous,
+            config,
+        )
+    };
+
+    let volume_ctrl = config.volume_ctrl != previous.volume_ctrl;
+    let latency_profile = config.audio_latency_profile != previous.audio_latency_profile;
+
+    if volume_ctrl || latency_profile {
+        {
+            let mut player = player.lock().await;
+
+            if volume_ctrl {
+                player.set_volume_control(config.volume_ctrl);
+            }
+
+            if latency_profile {
+                // The track is stopped either way, so carry on to stream again
+                match player.set_buffer_settings(buffer_settings(&config)) {
+                    Ok(()) => {
+                        if config.warmup {
+                            player.emitted_sink.warm_up();
+                        }
+                        if config.output_sample_rate != songbird::constants::SAMPLE_RATE_RAW as u32
+                        {
+                            player.emitted_sink.drain();
+                        }
+                    }
+                    Err(error) => {
+                        tracing::error!("Could not apply AUDIO_LATENCY_PROFILE: {}", error)
+                    }
+                }
+            }
+        }
+
+        // librespot only picks up the new mixer and sink with a new player
+        lib::player::rebuild_player(&player, |_| {}).await;
+
+        let voice_channel = player.lock().await.stats.voice_channel();
+        if let (true, Some((guild_id, channel_id))) = (latency_profile, voice_channel) {
+            if let Err(error) = lib::voice::join_and_play(ctx, &player, guild_id, channel_id).await
+            {
+                tracing::error!("Could not stream the new buffer to voice: {}", error);
+            }
+        }
+    }
+
+    tracing::info!("Reloaded config");
+
+    Ok(())
+}
+
+/// Buffering from `AUDIO_LATENCY_PROFILE`, with the buffer settings given explicitly.
+fn buffer_settings(config: &Config) -> BufferSettings {
+    let profile = config.audio_latency_profile.buffer_settings();
+    BufferSettings {
+        overrun: config.buffer_overrun,
+        underrun: config.buffer_underrun.unwrap_or(profile.underrun),
+        // More than the channel holds could never be collected
+        prebuffer: config.prebuffer_ms.map_or(profile.prebuffer, |ms| {
+            ((ms * config.output_sample_rate as u64 / 1000) as usize).min(profile.channel_capacity)
+        }),
+        ..profile
+    }
+}
+
+/// Guilds the bot acts in: only `GUILD_ID` if it's set, otherwise all of them.
+fn target_guilds(ctx: &Context, config: &Config) -> Vec<id::GuildId> {
+    let guilds = ctx.cache.guilds();
+
+    match config.guild_id {
+        Some(guild_id) => guilds.into_iter().filter(|g| g.0 == guild_id).collect(),
+        None => guilds,
+    }
+}
+
+/// The guild of voice channel `channel_id`, among the guilds the bot acts in.
+fn guild_of_channel(
+    ctx: &Context,
+    config: &Config,
+    channel_id: id::ChannelId,
+) -> Option<id::GuildId> {
+    target_guilds(ctx, config).into_iter().find(|x| {
+        ctx.cache
+            .guild(*x)
+            .map(|guild| guild.channels.contains_key(&channel_id))
+            .unwrap_or(false)
+    })
+}
+
+/// Authorized users in a voice channel of the guilds the bot acts in, with that channel.
+fn users_in_voice(ctx: &Context, config: &Config) -> Vec<(id::UserId, id::GuildId, id::ChannelId)> {
+    let user_ids = config.user_ids();
+
+    target_guilds(ctx, config)
+        .into_iter()
+        .filter_map(|guild_id| ctx.cache.guild(guild_id))
+        .flat_map(|guild| {
+            user_ids
+                .iter()
+                .filter_map(|user_id| {
+                    let channel_id = guild.voice_states.get(user_id)?.channel_id?;
+                    Some((*user_id, guild.id, channel_id))
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Voice channel of the user the bot follows. If it doesn't follow anyone in voice,
+/// one of the authorized users there is picked by `MULTI_USER_POLICY` and followed.
+fn followed_channel(
+    ctx: &Context,
+    config: &Config,
+    follower: &Follower,
+) -> Option<(id::GuildId, id::ChannelId)> {
+    let in_voice = users_in_voice(ctx, config);
+    let candidates: Vec<id::UserId> = in_voice.iter().map(|(user_id, _, _)| *user_id).collect();
+
+    let followed = follower
+        .followed()
+        .filter(|user_id| candidates.contains(user_id))
+        .or_else(|| follower.pick(config.multi_user_policy, &config.user_ids(), &candidates))?;
+    follower.follow(Some(followed));
+
+    in_voice
+        .into_iter()
+        .find(|(user_id, _, _)| *user_id == followed)
+        .map(|(_, guild_id, channel_id)| (guild_id, channel_id))
+}
+
+/// Move the bot to `channel_id` after it started following another user. If it
+/// isn't streaming, it joins their channel once playback starts.
+async fn follow_into(
+    ctx: &Context,
+    config: &Config,
+    player: &Arc<Mutex<SpotifyPlayer>>,
+    guild_id: Option<id::GuildId>,
+    channel_id: id::ChannelId,
+) {
+    let Some(guild_id) = guild_id.or_else(|| guild_of_channel(ctx, config, channel_id)) else {
+        return;
+    };
+
+    let streaming = player.lock().await.stats.voice_channel();
+
+    match streaming {
+        Some((_, current_channel_id)) if current_channel_id == channel_id => {}
+        Some((current_guild_id, _)) => {
+            lib::voice::leave(ctx, player, current_guild_id).await;
+
+            if let Err(error) = lib::voice::join_and_play(ctx, player, guild_id, channel_id).await {
+                join_failed(ctx, guild_id, channel_id, error).await;
+            }
+        }
+        None => player.lock().await.enable_connect().await,
+    }
+}
+
+/// Log why joining `channel_id` failed and tell the user, in the announce channel
+/// or else through the presence.
+async fn join_failed(
+    ctx: &Context,
+    guild_id: id::GuildId,
+    channel_id: id::ChannelId,
+    error: VoiceError,
+) {
+    if let VoiceError::SampleRate(_) = error {
+        // Only recording, as the startup warning said
+        tracing::debug!("Not joining voice channel {}: {}", channel_id, error);
+        return;
+    }
+
+    match error {
+        VoiceError::ChannelFull { .. } => tracing::warn!(
+            guild_id = %guild_id,
+            channel_id = %channel_id,
+            "Not joining voice channel {}: {}. Raise the user limit \
+            or give the bot the Move Members permission",
+            channel_id,
+            error
+        ),
+        _ => tracing::error!(
+            "Could not join voice channel {}: {} \
+            (check that the bot has the Connect and Speak permissions)",
+            channel_id,
+            error
+        ),
+    }
+
+    let manager = songbird::get(ctx)
+        .await
+        .expect("Songbird Voice client placed in at initialization.")
+        .clone();
+    let _ = manager.remove(guild_id).await;
+
+    let announcer = ctx.data.read().await.get::<AnnouncerKey>().unwrap().clone();
+
+    if announcer.enabled() {
+        announcer.say(
+            ctx.http.clone(),
+            format!("Could not join <#{}>: {}", channel_id, error),
+        );
+    } else {
+        set_presence(
+            ctx,
+            Some(gateway::Activity::playing(match error {
+                VoiceError::ChannelFull { .. } => "Voice channel is full",
+                _ => "Could not join voice",
+            })),
+            user::OnlineStatus::DoNotDisturb,
+        )
+        .await;
+    }
+}
+
+/// Warn if the configured user isn't in any guild with the bot, since it would never join voice.
+async fn check_user_guilds(ctx: &Context, guilds: &[id::GuildId], user_id: id::UserId) {
+    if guilds.is_empty() {
+        tracing::warn!("The bot isn't in any guild yet, invite it with the link above");
+        return;
+    }
+
+    // Without the privileged members intent the cache doesn't know every member, so ask the API
+    for guild_id in guilds {
+        if guild_id.member(ctx, user_id).await.is_ok() {
+            return;
+        }
+    }
+
+    tracing::warn!(
+        "Configured user {} not found in any shared guild, the bot will never activate. \
+        Check DISCORD_USER_ID and DISCORD_USER_IDS",
+        user_id
+    );
+}
+
+struct PresenceState {
+    // The presence to show, held back while reconnecting
+    current: Option<(Option<gateway::Activity>, user::OnlineStatus)>,
+    reconnects: usize,
+}
+
+static PRESENCE: std::sync::Mutex<PresenceState> = std::sync::Mutex::new(PresenceState {
+    current: None,
+    reconnects: 0,
+});
+
+/// Set the presence on every shard, `Context::set_presence` only changes the one
+/// the context belongs to. While reconnecting it's shown once that's done.
+pub async fn set_presence(
+    ctx: &Context,
+    activity: Option<gateway::Activity>,
+    status: user::OnlineStatus,
+) {
+    let shard_manager = ctx.data.read().await.get::<ShardManagerKey>().cloned();
+
+    let Some(shard_manager) = shard_manager else {
+        ctx.set_presence(activity, status).await;
+        return;
+    };
+
+    {
+        let mut presence = PRESENCE.lock().unwrap();
+        presence.current = Some((activity.clone(), status));
+        if presence.reconnects > 0 {
+            return;
+        }
+    }
+
+    show_presence(&shard_manager, activity, status).await;
+}
+
+async fn show_presence(
+    shard_manager: &Arc<Mutex<ShardManager>>,
+    activity: Option<gateway::Activity>,
+    status: user::OnlineStatus,
+) {
+    let shard_manager = shard_manager.lock().await;
+    for runner in shard_manager.runners.lock().await.values() {
+        runner.runner_tx.set_presence(activity.clone(), status);
+    }
+}
+
+/// Show that the connection to `what` is being restored, in the presence and with
+/// `ANNOUNCE_RECONNECT` in the announce channel, so the silence doesn't look like a crash.
+/// Call `reconnected` once done.
+pub async fn reconnecting(data: &RwLock<TypeMap>, http: &Arc<Http>, what: &str) {
+    let (shard_manager, announcer, announce) = {
+        let data = data.read().await;
+        (
+            data.get::<ShardManagerKey>().cloned(),
+            data.get::<AnnouncerKey>().unwrap().clone(),
+            data.get::<ConfigKey>().unwrap().announce_reconnect,
+        )
+    };
+
+    if announce {
+        announcer.say(
+            http.clone(),
+            format!("Lost the connection to {}, reconnecting…", what),
+        );
+    }
+
+    PRESENCE.lock().unwrap().reconnects += 1;
+
+    if let Some(shard_manager) = shard_manager {
+        show_presence(
+            &shard_manager,
+            Some(gateway::Activity::playing("Reconnecting…")),
+            user::OnlineStatus::Idle,
+        )
+        .await;
+    }
+}
+
+/// Restore the presence after `reconnecting`, telling whether it worked.
+pub async fn reconnected(data: &RwLock<TypeMap>, http: &Arc<Http>, what: &str, success: bool) {
+    let (shard_manager, announcer, announce, idle_activity) = {
+        let data = data.read().await;
+        let config = data.get::<ConfigKey>().unwrap();
+        (
+            data.get::<ShardManagerKey>().cloned(),
+            data.get::<AnnouncerKey>().unwrap().clone(),
+            config.announce_reconnect,
+            config.idle_activity(),
+        )
+    };
+
+    if announce {
+        let content = if success {
+            format!("Reconnected to {}.", what)
+        } else {
+            format!("Could not reconnect to {}.", what)
+        };
+        announcer.say(http.clone(), content);
+    }
+
+    let current = {
+        let mut presence = PRESENCE.lock().unwrap();
+        presence.reconnects = presence.reconnects.saturating_sub(1);
+        if presence.reconnects > 0 {
+            return;
+        }
+        presence.current.clone()
+    };
+
+    let (activity, status) = current.unwrap_or((idle_activity, user::OnlineStatus::Online));
+
+    if let Some(shard_manager) = shard_manager {
+        show_presence(&shard_manager, activity, status).await;
+    }
+}
+
+#[derive(Default)]
+struct Handler {
+    // Shards that were ready before
+    ready: std::sync::Mutex<HashSet<u64>>,
+    // Set once the first shard's cache is ready and Spotify events are handled
+    handling_events: AtomicBool,
+    // SIGHUP, listened to from the start but handled once there is a context to reload with
+    hangup: std::sync::Mutex<Option<Signal>>,
+}
 
 pub struct ConfigKey;
 impl TypeMapKey for ConfigKey {
     type Value = Config;
 }
 
+pub struct ShardManagerKey;
+impl TypeMapKey for ShardManagerKey {
+    type Value = Arc<Mutex<ShardManager>>;
+}
+
 #[async_trait]
 impl EventHandler for Handler {
-    async fn ready(&self, _ctx: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         println!("Ready!");
         println!("Invite me with https://discord.com/api/oauth2/authorize?client_id={}&permissions=36700160&scope=bot", ready.user.id);
+
+        // A second ready of a shard means the gateway had to start a new session,
+        // which may have left the voice connection dead
+        let shard = ready.shard.map_or(0, |[shard, _]| shard);
+        if !self.ready.lock().unwrap().insert(shard) {
+            revalidate_voice(&ctx).await;
+        }
+    }
+
+    async fn resume(&self, ctx: Context, _: ResumedEvent) {
+        revalidate_voice(&ctx).await;
     }
 
     async fn cache_ready(&self, ctx: Context, guilds: Vec<id::GuildId>) {
-        let data = ctx.data.read().await;
+        let (player, config, announcer, follower, metadata) = {
+            let data = ctx.data.read().await;
+            (
+                data.get::<SpotifyPlayerKey>().unwrap().clone(),
+                data.get::<ConfigKey>().unwrap().clone(),
+                data.get::<AnnouncerKey>().unwrap().clone(),
+                data.get::<FollowerKey>().unwrap().clone(),
+                data.get::<MetadataCacheKey>().unwrap().clone(),
+            )
+        };
+
+        let shard_count = ctx.cache.shard_count();
+
+        let guilds = match config.guild_id {
+            // Only the shard of the guild receives it
+            Some(guild_id) if utils::shard_id(guild_id, shard_count) != ctx.shard_id => Vec::new(),
+            Some(guild_id) if !guilds.contains(&guild_id.into()) => {
+                tracing::warn!(
+                    "The bot isn't in the configured GUILD_ID {}, it will never activate",
+                    guild_id
+                );
+                Vec::new()
+            }
+            Some(guild_id) => vec![guild_id.into()],
+            None => guilds,
+        };
 
-        let player = data.get::<SpotifyPlayerKey>().unwrap().clone();
-        let config = data.get::<ConfigKey>().unwrap().clone();
+        // Every shard only sees its own guilds, so the user may well be in another shard's
+        if shard_count == 1 && (config.guild_id.is_none() || !guilds.is_empty()) {
+            for user_id in config.user_ids() {
+                check_user_guilds(&ctx, &guilds, user_id).await;
+            }
+        }
 
         // Handle case when user is in VC when bot starts
         for guild_id in guilds {
-            let guild = ctx
-                .cache
-                .guild(guild_id)
-                .expect("Could not find guild in cache.");
-
-            let channel_id = guild
-                .voice_states
-                .get(&config.discord_user_id.into())
-                .and_then(|voice_state| voice_state.channel_id);
+            let Some(guild) = ctx.cache.guild(guild_id) else {
+                tracing::warn!("Guild {} is missing from the cache", guild_id);
+                continue;
+            };
+
+            let channel_id = config.user_ids().iter().find_map(|user_id| {
+                guild
+                    .voice_states
+                    .get(user_id)
+                    .and_then(|voice_state| voice_state.channel_id)
+            });
             drop(guild);
 
             if channel_id.is_some() {
@@ -68,7 +733,37 @@ impl EventHandler for Handler {
             }
         }
 
+        // Every shard's cache gets ready, but there is only one player to follow
+        if self.handling_events.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Some(mut hangup) = self.hangup.lock().unwrap().take() {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    if let Err(error) = reload_config(&ctx).await {
+                        tracing::error!("Could not reload config: {}", error);
+                    }
+                }
+            });
+        }
+
+        if config.adaptive_bitrate {
+            lib::adaptive::spawn(
+                player.clone(),
+                announcer.clone(),
+                ctx.http.clone(),
+                Bitrate::Bitrate320,
+            );
+        }
+
         let c = ctx.clone();
+        let skipper = Skipper::default();
+        // Set when a track ended, until the next one starts playing
+        let mut track_ended = false;
+        // Bumped when playback starts, cancelling a leave waiting for STOP_GRACE_SECS
+        let stop_generation = Arc::new(AtomicU64::new(0));
 
         // Handle Spotify events
         tokio::spawn(async move {
@@ -85,95 +780,221 @@ impl EventHandler for Handler {
                     }
                 };
 
+                tracing::debug!(event = ?event, "Spotify player event");
+                player.lock().await.events.handle(&event);
+
+                // Read the config for every event, it may have been reloaded
+                let config = c.data.read().await.get::<ConfigKey>().unwrap().clone();
+
                 match event {
                     PlayerEvent::Stopped { .. } => {
-                        c.set_presence(None, user::OnlineStatus::Online).await;
+                        skipper.clear();
+                        player.lock().await.clear_position();
 
-                        let manager = songbird::get(&c)
-                            .await
-                            .expect("Songbird Voice client placed in at initialization.")
-                            .clone();
-
-                        for guild_id in c.cache.guilds() {
-                            let _ = manager.remove(guild_id).await;
+                        // The old player stopping during !respotify
+                        if player.lock().await.restarting {
+                            continue;
                         }
+
+                        // Waiting for radio mode and the device tracker would hold up the other events
+                        let generation = stop_generation.load(Ordering::SeqCst);
+                        tokio::spawn(playback_stopped(
+                            c.clone(),
+                            player.clone(),
+                            announcer.clone(),
+                            config,
+                            stop_generation.clone(),
+                            generation,
+                        ));
                     }
 
                     PlayerEvent::Started { .. } => {
-                        let manager = songbird::get(&c)
-                            .await
-                            .expect("Songbird Voice client placed in at initialization.")
-                            .clone();
-
-                        // Search for guild and channel ids by user id
-                        let Some((guild_id, channel_id)) =
-                            c.cache.guilds().iter().find_map(|gid| {
-                                c.cache
-                                    .guild(gid)
-                                    .expect("Could not find guild in cache.")
-                                    .voice_states
-                                    .get(&config.discord_user_id.into())
-                                    .map(|state| (gid.to_owned(), state.channel_id.unwrap()))
-                            })
+                        stop_generation.fetch_add(1, Ordering::SeqCst);
+
+                        let Some((guild_id, channel_id)) = followed_channel(&c, &config, &follower)
                         else {
                             println!("Could not find user in VC.");
                             continue;
                         };
 
-                        let _handler = manager.join(guild_id, channel_id).await;
+                        // Reuse the track if we're still streaming to the right channel
+                        if lib::voice::resume(&player, Some(channel_id)).await {
+                            continue;
+                        }
 
-                        if let Some(handler_lock) = manager.get(guild_id) {
-                            let mut handler = handler_lock.lock().await;
+                        if let Err(error) =
+                            lib::voice::join_and_play(&c, &player, guild_id, channel_id).await
+                        {
+                            join_failed(&c, guild_id, channel_id, error).await;
+                        }
+                    }
 
-                            let mut decoder = input::codec::OpusDecoderState::new().unwrap();
-                            decoder.allow_passthrough = false;
+                    PlayerEvent::VolumeSet { volume } if !player.lock().await.volume_control() => {
+                        tracing::info!(
+                            "Ignoring Connect volume change to {}%, VOLUME_CTRL is fixed",
+                            volume as u32 * 100 / u16::MAX as u32
+                        );
+                    }
 
-                            let source = input::Input::new(
-                                true,
-                                input::reader::Reader::Extension(Box::new(
-                                    player.lock().await.emitted_sink.clone(),
-                                )),
-                                input::codec::Codec::FloatPcm,
-                                input::Container::Raw,
-                                None,
-                            );
+                    // Spirc marks the track and skips it once it comes up. Local files never
+                    // get this far, Spirc already skips them when loading the context
+                    PlayerEvent::Unavailable { track_id, .. } => {
+                        tracing::warn!(
+                            track_uri = %track_id.to_uri().unwrap_or_default(),
+                            "Track is unavailable, it will be skipped"
+                        );
+                    }
 
-                            handler.set_bitrate(songbird::driver::Bitrate::Auto);
+                    PlayerEvent::Loading { track_id, .. } => {
+                        skipper.loading(player.clone(), track_id);
+                    }
 
-                            handler.play_only_source(source);
-                        } else {
-                            println!("Could not fetch guild by ID.");
-                        }
+                    PlayerEvent::EndOfTrack { track_id, .. } => {
+                        track_ended = true;
+                        skipper.end_of_track(&player, track_id).await;
                     }
 
-                    PlayerEvent::Paused { .. } => {
-                        c.set_presence(None, user::OnlineStatus::Online).await;
+                    PlayerEvent::Paused {
+                        position_ms,
+                        duration_ms,
+                        ..
+                    } => {
+                        skipper.clear();
+                        player
+                            .lock()
+                            .await
+                            .set_position(position_ms, duration_ms, false);
+
+                        // Pausing right after a track ended means the context ran out
+                        if config.radio_mode && mem::take(&mut track_ended) {
+                            let generation = stop_generation.load(Ordering::SeqCst);
+                            let current_generation = stop_generation.clone();
+                            let (c, player) = (c.clone(), player.clone());
+
+                            // In a task of its own, not to hold up the other events
+                            tokio::spawn(async move {
+                                sleep(RADIO_RESTART_DELAY).await;
+
+                                if current_generation.load(Ordering::SeqCst) != generation
+                                    || restart_radio(&player, false).await
+                                {
+                                    return;
+                                }
+
+                                set_presence(
+                                    &c,
+                                    config.idle_activity(),
+                                    user::OnlineStatus::Online,
+                                )
+                                .await;
+                                lib::voice::pause(&player).await;
+                            });
+                            continue;
+                        }
+
+                        set_presence(&c, config.idle_activity(), user::OnlineStatus::Online).await;
+                        lib::voice::pause(&player).await;
                     }
 
-                    PlayerEvent::Playing { track_id, .. } => {
+                    PlayerEvent::Playing {
+                        track_id,
+                        position_ms,
+                        duration_ms,
+                        ..
+                    } => {
+                        stop_generation.fetch_add(1, Ordering::SeqCst);
+                        track_ended = false;
+                        skipper.clear();
+                        player
+                            .lock()
+                            .await
+                            .set_position(position_ms, duration_ms, true);
+                        lib::voice::resume(&player, None).await;
+
+                        if config.filter_explicit {
+                            let details: Result<TrackDetails, MercuryError> =
+                                metadata.get(track_id).await;
+
+                            if details.map(|d| d.explicit).unwrap_or(false) {
+                                tracing::info!(
+                                    track_uri = %track_id.to_uri().unwrap_or_default(),
+                                    "Skipping explicit track"
+                                );
+
+                                if let Some(spirc) = player.lock().await.spirc.as_ref() {
+                                    spirc.next();
+                                }
+                                continue;
+                            }
+                        }
+
                         let track: Result<librespot::metadata::Track, MercuryError> =
-                            librespot::metadata::Metadata::get(
-                                &player.lock().await.session,
-                                track_id,
-                            )
-                            .await;
+                            metadata.get(track_id).await;
+
+                        if let (Some(max_minutes), Ok(track)) =
+                            (config.max_track_minutes, track.as_ref())
+                        {
+                            if track.duration.max(0) as u64 > max_minutes * 60_000 {
+                                tracing::info!(
+                                    track_uri = %track_id.to_uri().unwrap_or_default(),
+                                    "Skipping track, it's longer than {} minutes",
+                                    max_minutes
+                                );
+                                announcer.say(
+                                    c.http.clone(),
+                                    format!(
+                                        "Skipped {}, tracks longer than {} minutes aren't allowed.",
+                                        track.name, max_minutes
+                                    ),
+                                );
+
+                                if let Some(spirc) = player.lock().await.spirc.as_ref() {
+                                    spirc.next();
+                                }
+                                continue;
+                            }
+                        }
+
+                        if player.lock().await.stats.track_playing(track_id) {
+                            tokio::spawn(log_track_start(player.clone(), track_id));
+                        }
 
                         if let Ok(track) = track {
                             let artist: Result<librespot::metadata::Artist, MercuryError> =
-                                librespot::metadata::Metadata::get(
-                                    &player.lock().await.session,
-                                    *track.artists.first().unwrap(),
-                                )
-                                .await;
+                                metadata.get(*track.artists.first().unwrap()).await;
 
                             if let Ok(artist) = artist {
-                                let listening_to = format!("{}: {}", artist.name, track.name);
+                                // Only fetch the album when the status shows it
+                                let album = if config.activity_format.contains("{album}") {
+                                    metadata
+                                        .get::<librespot::metadata::Album>(track.album)
+                                        .await
+                                        .map(|album| album.name)
+                                        .unwrap_or_default()
+                                } else {
+                                    String::new()
+                                };
+
+                                let listening_to =
+                                    config.activity_name(&artist.name, &track.name, &album);
 
-                                c.set_presence(
-                                    Some(gateway::Activity::listening(listening_to)),
+                                set_presence(
+                                    &c,
+                                    Some(
+                                        config
+                                            .activity_type
+                                            .activity(listening_to, config.activity_url.as_deref()),
+                                    ),
                                     user::OnlineStatus::Online,
                                 )
                                 .await;
+
+                                announcer.now_playing(
+                                    c.http.clone(),
+                                    metadata.clone(),
+                                    track,
+                                    artist.name,
+                                );
                             }
                         }
                     }
@@ -185,98 +1006,193 @@ impl EventHandler for Handler {
     }
 
     async fn voice_state_update(&self, ctx: Context, old: Option<VoiceState>, new: VoiceState) {
-        let data = ctx.data.read().await;
+        // Not holding the lock while handling the update, reload_config takes it for writing
+        let (config, player, follower) = {
+            let data = ctx.data.read().await;
+            (
+                data.get::<ConfigKey>().unwrap().clone(),
+                data.get::<SpotifyPlayerKey>().unwrap().clone(),
+                data.get::<FollowerKey>().unwrap().clone(),
+            )
+        };
 
-        let config = data.get::<ConfigKey>().unwrap();
+        // Voice states in other guilds don't matter when pinned to GUILD_ID
+        if let Some(guild_id) = config.guild_id.map(id::GuildId) {
+            let in_guild = |state: Option<&VoiceState>| {
+                state.and_then(|state| state.guild_id) == Some(guild_id)
+            };
 
-        if new.user_id.to_string() != config.discord_user_id.to_string() {
-            return;
+            if !in_guild(Some(&new)) && !in_guild(old.as_ref()) {
+                return;
+            }
         }
 
-        let player = data.get::<SpotifyPlayerKey>().unwrap();
+        let user_ids = config.user_ids();
+        let user_id = new.user_id;
 
-        // If user just connected
-        if old.clone().is_none() {
-            // Enable casting
-            player.lock().await.enable_connect().await;
+        if !user_ids.contains(&user_id) {
             return;
         }
 
-        // If user disconnected
-        if old.clone().unwrap().channel_id.is_some() && new.channel_id.is_none() {
-            // Disable casting
-            ctx.invisible().await;
-            player.lock().await.disable_connect().await;
+        let bot_channel_id = player
+            .lock()
+            .await
+            .stats
+            .voice_channel()
+            .map(|(_, channel_id)| channel_id);
+
+        let action = voice_state_action(old.as_ref(), &new, user_id, bot_channel_id);
+
+        match action {
+            VoiceStateAction::Joined | VoiceStateAction::Moved { .. } => follower.joined(user_id),
+            VoiceStateAction::Left { .. } => follower.left(user_id),
+            VoiceStateAction::Ignore => {}
+        }
 
-            // Disconnect
-            let manager = songbird::get(&ctx)
-                .await
-                .expect("Songbird Voice client placed in at initialization.")
-                .clone();
+        // Another authorized user than the one the bot follows
+        if follower
+            .followed()
+            .is_some_and(|followed| followed != user_id)
+        {
+            let moved_in = matches!(
+                action,
+                VoiceStateAction::Joined | VoiceStateAction::Moved { .. }
+            );
 
-            let _handler = manager.remove(new.guild_id.unwrap()).await;
+            if let (true, Some(channel_id)) = (moved_in, new.channel_id) {
+                if follower.prefers(config.multi_user_policy, &user_ids, user_id) {
+                    tracing::info!(
+                        user_id = %user_id,
+                        "Following {} into their voice channel ({:?})",
+                        user_id,
+                        config.multi_user_policy
+                    );
+                    follower.follow(Some(user_id));
+                    follow_into(&ctx, &config, &player, new.guild_id, channel_id).await;
+                }
+            }
 
             return;
         }
 
-        // If user moved channels
-        if old.clone().unwrap().channel_id.unwrap() != new.channel_id.unwrap() {
-            let bot_id = ctx.cache.current_user_id();
-
-            // A bit hacky way to get old guild id because
-            // its not present when switching voice channels
-            // for the first time for some reason
-            let old_guild_id = match old.clone().unwrap().guild_id {
-                Some(gid) => gid,
-                None => ctx
-                    .cache
-                    .guilds()
-                    .iter()
-                    .find(|x| {
-                        ctx.cache
-                            .guild(**x)
-                            .unwrap()
-                            .channels
-                            .iter()
-                            .any(|ch| ch.1.id() == new.channel_id.unwrap())
-                    })
-                    .unwrap()
-                    .to_owned(),
-            };
+        match action {
+            VoiceStateAction::Ignore => {}
 
-            let bot_channel = ctx
-                .cache
-                .guild(old_guild_id)
-                .unwrap()
-                .voice_states
-                .get(&bot_id)
-                .and_then(|voice_state| voice_state.channel_id);
+            VoiceStateAction::Joined => {
+                follower.follow(Some(user_id));
 
-            if Option::is_some(&bot_channel) {
-                let manager = songbird::get(&ctx)
-                    .await
-                    .expect("Songbird Voice client placed in at initialization.")
-                    .clone();
-
-                if old_guild_id != new.guild_id.unwrap() {
-                    let _handler = manager.remove(old_guild_id).await;
-                } else {
-                    let _handler = manager
-                        .join(new.guild_id.unwrap(), new.channel_id.unwrap())
-                        .await;
+                // Enable casting
+                player.lock().await.enable_connect().await;
+            }
+
+            VoiceStateAction::Left { guild_id } => {
+                // Stay for another authorized user who is still in voice
+                let in_voice: Vec<_> = users_in_voice(&ctx, &config)
+                    .into_iter()
+                    .filter(|(other_id, _, _)| *other_id != user_id)
+                    .collect();
+                let candidates: Vec<id::UserId> =
+                    in_voice.iter().map(|(other_id, _, _)| *other_id).collect();
+
+                if let Some((next_id, next_guild_id, next_channel_id)) = follower
+                    .pick(config.multi_user_policy, &user_ids, &candidates)
+                    .and_then(|next_id| in_voice.into_iter().find(|(id, _, _)| *id == next_id))
+                {
+                    tracing::info!(
+                        user_id = %next_id,
+                        "Followed user left voice, following {} instead",
+                        next_id
+                    );
+                    follower.follow(Some(next_id));
+                    follow_into(&ctx, &config, &player, Some(next_guild_id), next_channel_id).await;
+                    return;
+                }
+
+                follower.follow(None);
+
+                // Disable casting
+                set_presence(&ctx, None, user::OnlineStatus::Invisible).await;
+                player.lock().await.disable_connect().await;
+
+                // Disconnect
+                match guild_id {
+                    Some(guild_id) => lib::voice::leave(&ctx, &player, guild_id).await,
+                    None => {
+                        for guild_id in target_guilds(&ctx, &config) {
+                            lib::voice::leave(&ctx, &player, guild_id).await;
+                        }
+                    }
                 }
             }
 
-            return;
+            VoiceStateAction::Moved {
+                old_guild_id,
+                guild_id,
+                channel_id,
+            } => {
+                let bot_id = ctx.cache.current_user_id();
+
+                follower.follow(Some(user_id));
+
+                // The new state may lack the guild, the channel belongs to one
+                let guild_id = guild_id.or_else(|| guild_of_channel(&ctx, &config, channel_id));
+
+                // The old guild id isn't present when switching voice channels for the
+                // first time for some reason, but the bot can only have followed into
+                // the guild it streams to
+                let streaming_guild_id = player
+                    .lock()
+                    .await
+                    .stats
+                    .voice_channel()
+                    .map(|(guild_id, _)| guild_id);
+
+                let Some(old_guild_id) = old_guild_id.or(streaming_guild_id).or(guild_id) else {
+                    tracing::debug!("Could not tell which guild the user moved from");
+                    return;
+                };
+
+                let bot_channel = ctx.cache.guild(old_guild_id).and_then(|guild| {
+                    guild
+                        .voice_states
+                        .get(&bot_id)
+                        .and_then(|voice_state| voice_state.channel_id)
+                });
+
+                if bot_channel.is_none() {
+                    return;
+                }
+
+                match guild_id {
+                    Some(guild_id) if guild_id == old_guild_id => {
+                        if let Err(error) =
+                            lib::voice::join_and_play(&ctx, &player, guild_id, channel_id).await
+                        {
+                            // The user left the channel the bot still streams to
+                            lib::voice::leave(&ctx, &player, guild_id).await;
+                            join_failed(&ctx, guild_id, channel_id, error).await;
+                        }
+                    }
+                    _ => lib::voice::leave(&ctx, &player, old_guild_id).await,
+                }
+            }
         }
     }
 }
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
-
-    let framework = StandardFramework::new();
+    let framework = StandardFramework::new()
+        .configure(|c| c.prefix(lib::commands::COMMAND_PREFIX))
+        .bucket("control", |b| b.delay(lib::commands::CONTROL_COOLDOWN_SECS))
+        .await
+        .bucket("restart", |b| b.delay(lib::commands::RESTART_COOLDOWN_SECS))
+        .await
+        .on_dispatch_error(lib::commands::dispatch_error)
+        .group(&lib::commands::PLAYBACK_GROUP)
+        .group(&lib::commands::STATUS_GROUP)
+        .group(&lib::commands::ADMIN_GROUP)
+        .help(&lib::commands::HELP);
 
     let config = match Config::new() {
         Ok(config) => config,
@@ -292,38 +1208,312 @@ async fn main() {
         }
     };
 
+    match config.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(EnvFilter::from_default_env())
+            .init(),
+    }
+
+    let credentials = match config.spotify_credentials() {
+        Ok(credentials) => credentials,
+        Err(error) => {
+            println!("Couldn't read config");
+            println!("{}", error);
+            exit(1)
+        }
+    };
+
+    let initial_context = match config
+        .initial_context
+        .as_deref()
+        .map(PlayTarget::parse)
+        .transpose()
+    {
+        Ok(initial_context) => initial_context,
+        Err(error) => {
+            println!("Couldn't read config");
+            println!("Invalid INITIAL_CONTEXT: {}", error);
+            exit(1)
+        }
+    };
+
     let mut cache_dir = None;
 
     if let Ok(c) = env::var("CACHE_DIR") {
         cache_dir = Some(c);
     }
 
-    let player = Arc::new(Mutex::new(
-        SpotifyPlayer::new(
-            config.spotify_username.clone(),
-            config.spotify_password.clone(),
-            Bitrate::Bitrate320,
-            cache_dir,
-            config.spotify_bot_autoplay,
-            config.spotify_device_name.clone(),
-        )
-        .await,
+    let player = SpotifyPlayer::builder()
+        .credentials(credentials)
+        .cache(cache_dir)
+        .max_login_attempts(config.max_reconnect_attempts)
+        .login_timeout(Duration::from_secs(config.login_timeout_secs))
+        .bitrate(Bitrate::Bitrate320)
+        .normalisation(config.normalisation)
+        .track_gain_only(config.track_gain_only)
+        // Radio mode relies on autoplay to keep going after the context ends
+        .autoplay(config.spotify_bot_autoplay || config.radio_mode)
+        .device_name(config.spotify_device_name.clone())
+        .sample_format(config.sample_format)
+        .output_sample_rate(config.output_sample_rate)
+        .buffer_settings(buffer_settings(&config))
+        .volume_control(config.volume_ctrl)
+        .initial_context(initial_context)
+        .build()
+        .await;
+
+    let player = match player {
+        Ok(player) => Arc::new(Mutex::new(player)),
+        Err(error) => {
+            println!("Could not start the Spotify player: {}", error);
+            exit(1)
+        }
+    };
+
+    if config.warmup {
+        player.lock().await.emitted_sink.warm_up();
+    }
+
+    if let Some(path) = config.record_path.as_ref() {
+        match WavRecorder::create(path, config.output_sample_rate) {
+            Ok(recorder) => {
+                tracing::info!("Recording audio to {}", path);
+                player.lock().await.emitted_sink.add_tap(Box::new(recorder));
+            }
+            Err(error) => tracing::warn!("Could not create recording {}: {}", path, error),
+        }
+    }
+
+    if let Some(path) = config.stats_path.as_ref() {
+        let stats = player.lock().await.stats.clone();
+
+        if let Err(error) = stats.persist_to(path.into()) {
+            println!("Could not read STATS_PATH {}: {}", path, error);
+            exit(1)
+        }
+
+        let periodic = stats.clone();
+        let periodic_player = player.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(STATS_SAVE_INTERVAL).await;
+
+                periodic_player.lock().await.remember_guild_volume();
+                if let Err(error) = periodic.save() {
+                    tracing::warn!("Could not save stats: {}", error);
+                }
+            }
+        });
+
+        // Save on shutdown too, so the last minute isn't lost
+        let player = player.clone();
+        tokio::spawn(async move {
+            let (Ok(mut terminate), Ok(mut interrupt)) = (
+                signal(SignalKind::terminate()),
+                signal(SignalKind::interrupt()),
+            ) else {
+                tracing::warn!(
+                    "Could not listen for shutdown signals, stats are saved every minute"
+                );
+                return;
+            };
+
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = interrupt.recv() => {}
+            }
+
+            player.lock().await.remember_guild_volume();
+            if let Err(error) = stats.save() {
+                tracing::warn!("Could not save stats: {}", error);
+            }
+
+            exit(0)
+        });
+    }
+
+    if config.output_sample_rate != songbird::constants::SAMPLE_RATE_RAW as u32 {
+        if config.output_sample_rate == 0 || config.record_path.is_none() {
+            println!(
+                "OUTPUT_SAMPLE_RATE other than {} only works with RECORD_PATH, Discord can't play it",
+                songbird::constants::SAMPLE_RATE_RAW
+            );
+            exit(1)
+        }
+
+        tracing::warn!(
+            "OUTPUT_SAMPLE_RATE is {} Hz, audio only goes to {}",
+            config.output_sample_rate,
+            config.record_path.as_deref().unwrap_or_default()
+        );
+        player.lock().await.emitted_sink.drain();
+    }
+
+    let metadata = Arc::new(MetadataCache::new(
+        player.lock().await.session.clone(),
+        config.metadata_cache_size,
+        Duration::from_secs(config.metadata_cache_ttl),
     ));
 
-    let mut client = Client::builder(
-        &config.discord_token,
-        gateway::GatewayIntents::non_privileged(),
-    )
-    .event_handler(Handler)
-    .framework(framework)
-    .type_map_insert::<SpotifyPlayerKey>(player)
-    .type_map_insert::<ConfigKey>(config)
-    .register_songbird()
-    .await
-    .expect("Err creating client");
-
-    let _ = client
-        .start()
+    {
+        let player = player.lock().await;
+        tracing::info!(
+            "Spotify session country: {}, explicit filter: {}",
+            player.session.country(),
+            if config.filter_explicit { "on" } else { "off" }
+        );
+    }
+
+    if config.activity_type == ActivityType::Streaming
+        && config
+            .activity_url
+            .as_deref()
+            .and_then(|url| reqwest::Url::parse(url).ok())
+            .is_none()
+    {
+        tracing::warn!(
+            "ACTIVITY_TYPE=streaming needs a valid ACTIVITY_URL, showing as playing instead"
+        );
+    }
+
+    let mut http = HttpBuilder::new(&config.discord_token);
+
+    // Only the REST API can be proxied, see the README
+    if let Some(proxy) = config.discord_proxy.as_ref() {
+        if !proxy.starts_with("http://") && !proxy.starts_with("https://") {
+            println!("DISCORD_PROXY must be an http:// or https:// proxy URL");
+            exit(1)
+        }
+
+        let client = reqwest::Proxy::all(proxy.as_str())
+            .and_then(|proxy| reqwest::Client::builder().proxy(proxy).build())
+            .unwrap_or_else(|error| {
+                println!("Invalid DISCORD_PROXY: {}", error);
+                exit(1)
+            });
+
+        http = http.client(client);
+    }
+
+    // Guilds fill the cache, voice states tell when the user joins a channel and messages
+    // carry the commands. Message content is privileged and must be enabled in the portal
+    let intents = gateway::GatewayIntents::GUILDS
+        | gateway::GatewayIntents::GUILD_VOICE_STATES
+        | gateway::GatewayIntents::GUILD_MESSAGES
+        | gateway::GatewayIntents::DIRECT_MESSAGES
+        | gateway::GatewayIntents::MESSAGE_CONTENT;
+
+    let shard_count = config.shard_count;
+
+    let hangup = signal(SignalKind::hangup())
+        .map_err(|_| {
+            tracing::warn!("Could not listen for SIGHUP, use !reload to reload the config")
+        })
+        .ok();
+
+    let mut client = ClientBuilder::new_with_http(http.build(), intents)
+        .event_handler(Handler {
+            hangup: std::sync::Mutex::new(hangup),
+            ..Handler::default()
+        })
+        .framework(framework)
+        .type_map_insert::<SpotifyPlayerKey>(player.clone())
+        .type_map_insert::<MetadataCacheKey>(metadata.clone())
+        .type_map_insert::<AnnouncerKey>(Announcer::new(config.announce_channel_id))
+        .type_map_insert::<FollowerKey>(Arc::new(Follower::default()))
+        .type_map_insert::<ConfigKey>(config)
+        .register_songbird()
         .await
-        .map_err(|why| println!("Client ended: {:?}", why));
+        .expect("Err creating client");
+
+    client
+        .data
+        .write()
+        .await
+        .insert::<ShardManagerKey>(client.shard_manager.clone());
+
+    // Spotify closes sessions now and then, e.g. on bots that have been running for
+    // days. Playback, metadata and lyrics all stop working with it, so log in again
+    {
+        let player = player.clone();
+        let metadata = metadata.clone();
+        let data = client.data.clone();
+        let http = client.cache_and_http.http.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = sleep(SESSION_CHECK_INTERVAL) => {}
+                    _ = metadata.session_broken() => {}
+                }
+
+                let relogin = {
+                    let player = player.lock().await;
+                    if !player.session.is_invalid() {
+                        continue;
+                    }
+                    player.relogin()
+                };
+
+                let Some(relogin) = relogin else {
+                    continue;
+                };
+
+                tracing::warn!("Spotify closed the session, logging in again");
+                reconnecting(&data, &http, "Spotify").await;
+
+                let success = match relogin.await {
+                    Ok(session) => {
+                        metadata.set_session(session.clone());
+                        player.lock().await.replace_session(session).await;
+                        tracing::info!("Logged in to Spotify again");
+                        true
+                    }
+                    Err(error) => {
+                        tracing::error!("Could not log in to Spotify again: {}", error);
+                        false
+                    }
+                };
+
+                reconnected(&data, &http, "Spotify", success).await;
+
+                let exit_on_failure = data
+                    .read()
+                    .await
+                    .get::<ConfigKey>()
+                    .unwrap()
+                    .exit_on_reconnect_failure;
+
+                if !success && exit_on_failure {
+                    tracing::error!(
+                        "Could not log in to Spotify again after MAX_RECONNECT_ATTEMPTS, \
+                        exiting so the supervisor can restart the bot"
+                    );
+                    exit(1);
+                }
+            }
+        });
+    }
+
+    let result = match shard_count {
+        None => client.start().await,
+        Some(ShardCount::Fixed(shards)) => client.start_shards(shards).await,
+        Some(ShardCount::Auto(_)) => client.start_autosharded().await,
+    };
+
+    match result {
+        Err(serenity::Error::Gateway(
+            serenity::gateway::GatewayError::DisallowedGatewayIntents,
+        )) => {
+            println!(
+                "Discord refused the gateway intents, enable the Message Content Intent \
+                for the bot in the developer portal"
+            );
+            exit(1)
+        }
+        Err(why) => println!("Client ended: {:?}", why),
+        Ok(()) => {}
+    }
 }